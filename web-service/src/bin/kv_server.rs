@@ -1,33 +1,96 @@
-use actix_web::{error, web, App, HttpRequest, HttpResponse, HttpServer};
-use kvweb;
+use actix_web::{middleware, web, App, HttpServer};
+use kvweb::config::{Config, QueueBackend};
+use kvweb::queue::{JobQueue, MemoryQueue, OcypodQueue};
+use std::path::PathBuf;
+use std::process;
+use std::sync::Arc;
+use std::time::Duration;
+use structopt::StructOpt;
+use tokio::sync::oneshot;
 
-fn json_error_handler(err: error::JsonPayloadError, _req: &HttpRequest) -> error::Error {
-    let msg = String::from("Please update your plugin");
-    let resp = HttpResponse::BadRequest().body(msg);
-    error::InternalError::from_response(err, resp).into()
+#[derive(StructOpt)]
+struct Cli {
+    /// Configuration file (TOML)
+    #[structopt(long, short, env = "KVFINDER_CONFIG", parse(from_os_str))]
+    config: Option<PathBuf>,
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     println!("KVFinder webserver started");
+    let args = Cli::from_args();
+    let config = Config::load(args.config.as_deref()).unwrap_or_else(|e| {
+        eprintln!("Error loading configuration: {}", e);
+        process::exit(1);
+    });
 
-    // job timeout 30 minutes, expires after 1 day
-    kvweb::webserver::create_ocypod_queue("kvfinder", "30m", "1d", 0);
+    // with the memory backend, jobs are processed by a worker running in this process
+    let (queue, poll_interval): (Arc<dyn JobQueue>, Option<Duration>) = match config.queue.backend {
+        QueueBackend::Ocypod => {
+            let ocypod = OcypodQueue::new(&config.queue.url, &config.queue.name);
+            if let Err(e) = ocypod.create_queue(
+                &config.queue.timeout,
+                &config.queue.expires_after,
+                config.queue.retries,
+            )
+            .await
+            {
+                println!("Error creating queue: {}", e);
+            }
+            (Arc::new(ocypod), None)
+        }
+        QueueBackend::Memory => {
+            let expires_after = humantime::parse_duration(&config.queue.expires_after).unwrap_or_else(|e| {
+                eprintln!("Invalid queue.expires_after: {}", e);
+                process::exit(1);
+            });
+            let poll_interval = config.worker.check().unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                process::exit(1);
+            });
+            (Arc::new(MemoryQueue::new(expires_after)), Some(poll_interval))
+        }
+    };
+    let worker = poll_interval.map(|poll_interval| {
+        println!("Using in-memory queue, jobs are processed by this server");
+        let (stop, stopped) = oneshot::channel::<()>();
+        let worker = tokio::spawn(kvweb::worker::run(
+            queue.clone(),
+            config.worker.clone(),
+            poll_interval,
+            async {
+                let _ = stopped.await;
+            },
+        ));
+        (stop, worker)
+    });
+    let json_limit = config.server.json_limit;
+    let routes = kvweb::webserver::routes(&config.server);
+    let policy = web::Data::new(config.validation.clone());
 
-    HttpServer::new(|| {
+    HttpServer::new(move || {
         App::new()
+            // compress responses (gzip, br or zstd) as negotiated with Accept-Encoding
+            .wrap(middleware::Compress::default())
+            .app_data(web::Data::from(queue.clone()))
+            .app_data(policy.clone())
             .app_data(
                 web::JsonConfig::default()
-                    .limit(5_000_000)
-                    .error_handler(json_error_handler),
+                    .limit(json_limit)
+                    .error_handler(kvweb::webserver::json_error_handler),
             )
-            .route("/", web::get().to(kvweb::webserver::hello))
-            .route("/{id}", web::get().to(kvweb::webserver::ask))
-            .route("/retrieve-input/{id}", web::get().to(kvweb::webserver::retrieve_input))
-            .route("/create", web::post().to(kvweb::webserver::create))
+            .app_data(web::QueryConfig::default().error_handler(kvweb::webserver::query_error_handler))
+            .service(web::scope("/v1").configure(routes.clone()))
+            .configure(routes.clone())
     })
-    .bind("0.0.0.0:8081")
-    .expect("Cannot bind to port 8081")
+    .bind(&config.server.bind)
+    .unwrap_or_else(|e| panic!("Cannot bind to {}: {}", config.server.bind, e))
     .run()
-    .await
+    .await?;
+    // the server stopped (SIGINT or SIGTERM), let the embedded worker finish its jobs
+    if let Some((stop, worker)) = worker {
+        let _ = stop.send(());
+        let _ = worker.await;
+    }
+    Ok(())
 }
@@ -1,39 +1,54 @@
-use kvweb;
-use std::{thread, time};
+use kvweb::config::{Config, QueueBackend};
+use kvweb::queue::{JobQueue, OcypodQueue};
+use std::path::PathBuf;
+use std::process;
+use std::sync::Arc;
+use tokio::signal::unix::{signal, SignalKind};
 use structopt::StructOpt;
 
 #[derive(StructOpt)]
 struct Cli {
-    // KVFinder path
-    kv_path: String,
-    // path to save jobs
-    job_path: String,
+    /// Configuration file (TOML)
+    #[structopt(long, short, env = "KVFINDER_CONFIG", parse(from_os_str))]
+    config: Option<PathBuf>,
+    /// KVFinder path (overrides worker.kv_path)
+    kv_path: Option<String>,
+    /// path to save jobs (overrides worker.job_path)
+    job_path: Option<String>,
 }
 
-fn main() {
+#[tokio::main]
+async fn main() {
     println!("KVFinder Worker started");
     let args = Cli::from_args();
-    let config = kvweb::worker::Config {
-        kv_path: args.kv_path,
-        job_path: args.job_path,
-    };
-    loop {
-        // get the next job from queue. If there is not a job to process then wait 5 seconds.
-        let r = kvweb::worker::get_job();
-        match r {
-            Ok(j) => {
-                let id = j.id;
-                // process a job and submit the results (update job at the queue).
-                match kvweb::worker::process(j, &config) {
-                    Err(e) => println!("Error processing: {}", e),
-                    Ok(output) => match kvweb::worker::submit_result(id, output) {
-                        Ok(id) => println!("Job processed successfully: {}", id),
-                        Err(e) => println!("Error submitting result to queue: {}", e),
-                    },
-                }
-            }
-            //no job to process
-            Err(_) => thread::sleep(time::Duration::from_secs(5)),
-        }
+    let mut config = Config::load(args.config.as_deref()).unwrap_or_else(|e| {
+        eprintln!("Error loading configuration: {}", e);
+        process::exit(1);
+    });
+    if let Some(kv_path) = args.kv_path {
+        config.worker.kv_path = kv_path;
+    }
+    if let Some(job_path) = args.job_path {
+        config.worker.job_path = job_path;
+    }
+    if config.queue.backend == QueueBackend::Memory {
+        eprintln!("With queue.backend = \"memory\" jobs are processed by kv_server, not by kv_worker");
+        process::exit(1);
+    }
+    let poll_interval = config.worker.check().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+    let queue: Arc<dyn JobQueue> = Arc::new(OcypodQueue::new(&config.queue.url, &config.queue.name));
+    kvweb::worker::run(queue, config.worker, poll_interval, shutdown_signal()).await;
+    println!("KVFinder Worker stopped");
+}
+
+/// Resolves on SIGINT (ctrl-c) or SIGTERM (docker stop).
+async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("cannot listen to SIGTERM");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
     }
 }
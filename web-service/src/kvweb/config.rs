@@ -0,0 +1,256 @@
+use super::KVSResolution;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Configuration shared by the webserver (kv_server) and the worker (kv_worker).
+/// It is read from a TOML file with sections `[queue]`, `[server]`, `[worker]`
+/// and `[validation]`.
+/// Missing keys take default values, which are the ones used by docker-compose.
+/// Scalar values can be overridden by an environment variable (see `apply_env`).
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub queue: QueueConfig,
+    pub server: ServerConfig,
+    pub worker: WorkerConfig,
+    pub validation: ValidationPolicy,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct QueueConfig {
+    pub backend: QueueBackend,
+    /// Ocypod server address
+    pub url: String,
+    pub name: String,
+    /// Time a job can be running before ocypod marks it as timed out
+    pub timeout: String,
+    /// Time a finished job is kept in the queue
+    pub expires_after: String,
+    pub retries: i32,
+}
+
+/// Job queue of the webserver and the worker
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum QueueBackend {
+    /// Ocypod server at `url`, shared by kv_server and kv_worker processes
+    #[default]
+    Ocypod,
+    /// In-process queue (`MemoryQueue`): kv_server also processes the jobs and
+    /// they are lost when it stops. To run the service without Ocypod/Redis.
+    Memory,
+}
+
+impl FromStr for QueueBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ocypod" => Ok(QueueBackend::Ocypod),
+            "memory" => Ok(QueueBackend::Memory),
+            _ => Err(format!("unknown queue backend `{}`, expected `ocypod` or `memory`", s)),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind: String,
+    /// Maximum size (bytes) of a json request
+    pub json_limit: usize,
+    /// Maximum size (bytes) of a json batch request (POST /batch)
+    pub batch_json_limit: usize,
+    /// Maximum size (bytes) of an uploaded file (multipart POST /create),
+    /// also applied to gzip files after decompression
+    pub upload_limit: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct WorkerConfig {
+    /// parKVFinder path (directory with parKVFinder binary and dictionary)
+    pub kv_path: String,
+    /// path to save jobs
+    pub job_path: String,
+    /// Time to wait before asking the queue again when there is not a job to process
+    pub poll_interval: String,
+    /// Time a parKVFinder run can take before it is killed and its job fails.
+    /// Shorter than queue.timeout, so jobs fail before ocypod times them out.
+    pub timeout: String,
+    /// Jobs processed at the same time by a worker
+    pub concurrency: usize,
+    /// Threads of a parKVFinder run (OMP_NUM_THREADS); 0 leaves it unset (all
+    /// cores, shared among concurrent jobs)
+    pub threads: usize,
+    /// Maximum memory (bytes of address space) of a parKVFinder run; 0 for no limit
+    pub memory_limit: u64,
+    /// Maximum CPU time (seconds, of all threads) of a parKVFinder run; 0 for no limit
+    pub cpu_time_limit: u64,
+}
+
+/// Limits applied by `Input::check` to jobs received by the webserver. They
+/// prevent heavy jobs that could block or slow down the workers.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ValidationPolicy {
+    pub probe_in_max: f64,
+    pub probe_out_max: f64,
+    pub removal_distance_max: f64,
+    /// Resolution modes accepted in settings.modes.resolution_mode
+    pub resolution_modes: Vec<KVSResolution>,
+    /// Accept cavity representation (settings.modes.kvp_mode)
+    pub allow_kvp_mode: bool,
+    /// Maximum number of atoms of the processed model
+    pub max_atoms: usize,
+    /// Maximum volume (cubic angstroms) of the box in box mode
+    pub max_box_volume: f64,
+    /// Distance (angstroms) a box corner can be off in box geometry checks
+    pub box_tolerance: f64,
+    /// Maximum number of jobs of a batch (POST /batch)
+    pub max_batch_size: usize,
+}
+
+impl Default for ValidationPolicy {
+    fn default() -> Self {
+        ValidationPolicy {
+            probe_in_max: 5.0,
+            probe_out_max: 50.0,
+            removal_distance_max: 10.0,
+            resolution_modes: vec![KVSResolution::Low],
+            allow_kvp_mode: false,
+            max_atoms: 100_000,
+            max_box_volume: 1_000_000.0,
+            box_tolerance: 0.1,
+            max_batch_size: 500,
+        }
+    }
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        QueueConfig {
+            backend: QueueBackend::Ocypod,
+            url: String::from("http://ocypod:8023"),
+            name: String::from("kvfinder"),
+            // job timeout 30 minutes, expires after 1 day
+            timeout: String::from("30m"),
+            expires_after: String::from("1d"),
+            retries: 0,
+        }
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            bind: String::from("0.0.0.0:8081"),
+            json_limit: 5_000_000,
+            batch_json_limit: 100_000_000,
+            upload_limit: 5_000_000,
+        }
+    }
+}
+
+impl Default for WorkerConfig {
+    fn default() -> Self {
+        WorkerConfig {
+            kv_path: String::from("/usr/local/parKVFinder"),
+            job_path: String::from("/jobs"),
+            poll_interval: String::from("5s"),
+            timeout: String::from("25m"),
+            concurrency: 1,
+            threads: 0,
+            memory_limit: 0,
+            cpu_time_limit: 0,
+        }
+    }
+}
+
+impl Config {
+    /// Read configuration from a TOML file (or use defaults if `path` is None)
+    /// and apply environment variable overrides.
+    pub fn load(path: Option<&Path>) -> Result<Config, Box<dyn Error>> {
+        let mut config = match path {
+            Some(p) => toml::from_str(&fs::read_to_string(p)?)
+                .map_err(|e| format!("Invalid config file {}: {}", p.display(), e))?,
+            None => Config::default(),
+        };
+        config.apply_env()?;
+        Ok(config)
+    }
+
+    /// Override values with environment variables, if they are set:
+    /// KVFINDER_QUEUE_BACKEND, KVFINDER_QUEUE_URL, KVFINDER_QUEUE_NAME, KVFINDER_QUEUE_TIMEOUT,
+    /// KVFINDER_QUEUE_EXPIRES_AFTER, KVFINDER_QUEUE_RETRIES, KVFINDER_BIND,
+    /// KVFINDER_JSON_LIMIT, KVFINDER_BATCH_JSON_LIMIT, KVFINDER_UPLOAD_LIMIT,
+    /// KVFINDER_KV_PATH, KVFINDER_JOB_PATH, KVFINDER_POLL_INTERVAL, KVFINDER_JOB_TIMEOUT,
+    /// KVFINDER_CONCURRENCY, KVFINDER_THREADS, KVFINDER_MEMORY_LIMIT, KVFINDER_CPU_TIME_LIMIT, KVFINDER_PROBE_IN_MAX,
+    /// KVFINDER_PROBE_OUT_MAX, KVFINDER_REMOVAL_DISTANCE_MAX, KVFINDER_ALLOW_KVP_MODE, KVFINDER_MAX_ATOMS,
+    /// KVFINDER_MAX_BOX_VOLUME, KVFINDER_BOX_TOLERANCE and KVFINDER_MAX_BATCH_SIZE.
+    pub fn apply_env(&mut self) -> Result<(), Box<dyn Error>> {
+        override_from_env(&mut self.queue.backend, "KVFINDER_QUEUE_BACKEND")?;
+        override_from_env(&mut self.queue.url, "KVFINDER_QUEUE_URL")?;
+        override_from_env(&mut self.queue.name, "KVFINDER_QUEUE_NAME")?;
+        override_from_env(&mut self.queue.timeout, "KVFINDER_QUEUE_TIMEOUT")?;
+        override_from_env(&mut self.queue.expires_after, "KVFINDER_QUEUE_EXPIRES_AFTER")?;
+        override_from_env(&mut self.queue.retries, "KVFINDER_QUEUE_RETRIES")?;
+        override_from_env(&mut self.server.bind, "KVFINDER_BIND")?;
+        override_from_env(&mut self.server.json_limit, "KVFINDER_JSON_LIMIT")?;
+        override_from_env(&mut self.server.batch_json_limit, "KVFINDER_BATCH_JSON_LIMIT")?;
+        override_from_env(&mut self.server.upload_limit, "KVFINDER_UPLOAD_LIMIT")?;
+        override_from_env(&mut self.worker.kv_path, "KVFINDER_KV_PATH")?;
+        override_from_env(&mut self.worker.job_path, "KVFINDER_JOB_PATH")?;
+        override_from_env(&mut self.worker.poll_interval, "KVFINDER_POLL_INTERVAL")?;
+        override_from_env(&mut self.worker.timeout, "KVFINDER_JOB_TIMEOUT")?;
+        override_from_env(&mut self.worker.concurrency, "KVFINDER_CONCURRENCY")?;
+        override_from_env(&mut self.worker.threads, "KVFINDER_THREADS")?;
+        override_from_env(&mut self.worker.memory_limit, "KVFINDER_MEMORY_LIMIT")?;
+        override_from_env(&mut self.worker.cpu_time_limit, "KVFINDER_CPU_TIME_LIMIT")?;
+        override_from_env(&mut self.validation.probe_in_max, "KVFINDER_PROBE_IN_MAX")?;
+        override_from_env(&mut self.validation.probe_out_max, "KVFINDER_PROBE_OUT_MAX")?;
+        override_from_env(
+            &mut self.validation.removal_distance_max,
+            "KVFINDER_REMOVAL_DISTANCE_MAX",
+        )?;
+        override_from_env(&mut self.validation.allow_kvp_mode, "KVFINDER_ALLOW_KVP_MODE")?;
+        override_from_env(&mut self.validation.max_atoms, "KVFINDER_MAX_ATOMS")?;
+        override_from_env(&mut self.validation.max_box_volume, "KVFINDER_MAX_BOX_VOLUME")?;
+        override_from_env(&mut self.validation.box_tolerance, "KVFINDER_BOX_TOLERANCE")?;
+        override_from_env(&mut self.validation.max_batch_size, "KVFINDER_MAX_BATCH_SIZE")?;
+        Ok(())
+    }
+}
+
+impl WorkerConfig {
+    /// Check the values read when jobs are processed, so a bad configuration
+    /// is reported at startup. Returns the poll interval.
+    pub fn check(&self) -> Result<Duration, String> {
+        let poll_interval = humantime::parse_duration(&self.poll_interval)
+            .map_err(|e| format!("Invalid worker.poll_interval: {}", e))?;
+        humantime::parse_duration(&self.timeout).map_err(|e| format!("Invalid worker.timeout: {}", e))?;
+        if self.concurrency == 0 {
+            return Err(String::from("Invalid worker.concurrency: must be at least 1"));
+        }
+        Ok(poll_interval)
+    }
+}
+
+fn override_from_env<T>(value: &mut T, name: &str) -> Result<(), Box<dyn Error>>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    if let Ok(v) = env::var(name) {
+        *value = v
+            .parse()
+            .map_err(|e| format!("Invalid value for {}: {}", name, e))?;
+    }
+    Ok(())
+}
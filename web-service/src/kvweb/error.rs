@@ -0,0 +1,210 @@
+use super::queue::QueueError;
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+use serde::Serialize;
+use std::fmt;
+
+/// Stable error codes sent to clients. They are serialized in SCREAMING_SNAKE_CASE
+/// (e.g. `ProbeOutRange` -> "PROBE_OUT_RANGE") and must not be renamed.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+    InvalidJson,
+    InvalidUpload,
+    InvalidParameters,
+    InvalidQuery,
+    ModesConflict,
+    ResolutionRestricted,
+    ProbeInRange,
+    ProbeOutRange,
+    ProbeOutLessThanProbeIn,
+    RemovalDistanceRange,
+    VolumeCutoffRange,
+    KvpModeRestricted,
+    LigandMissing,
+    LigandModeDisabled,
+    LigandCutoffRange,
+    BoxOutOfBounds,
+    BoxVolumeRange,
+    BoxDegenerate,
+    BoxNotRectangular,
+    BoxPaddingMismatch,
+    BoxModeDisabled,
+    BoxSelectionEmpty,
+    BoxPaddingRange,
+    PdbParsing,
+    EmptyStructure,
+    ModelNotFound,
+    TooManyAtoms,
+    PdbColumnOverflow,
+    BatchSizeRange,
+    JobNotFound,
+    JobNotCompleted,
+    BatchNotFound,
+    QueueUnavailable,
+    QueueError,
+    CorruptedJobData,
+    InternalError,
+}
+
+/// A rule broken by the input sent by a client. `field` is the path of the
+/// offending value, e.g. "settings.probes.probe_out".
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Violation {
+    pub code: ErrorCode,
+    pub message: String,
+    pub field: String,
+}
+
+impl Violation {
+    pub fn new(code: ErrorCode, field: &str, message: &str) -> Violation {
+        Violation {
+            code,
+            message: message.to_string(),
+            field: field.to_string(),
+        }
+    }
+}
+
+/// Errors returned by the webserver. They are sent to clients as json:
+/// `{"code": "PROBE_OUT_RANGE", "message": "...", "field": "settings.probes.probe_out"}`
+/// where `field` is null if the error is not related to an input field.
+/// Invalid inputs also carry `errors`, the list of every violation found; the
+/// first one is repeated in `code`, `message` and `field`.
+#[derive(Debug)]
+pub enum KvError {
+    /// Request body is not valid json or does not match the input schema.
+    /// `field` is the path of the value that could not be read, if known.
+    InvalidJson { field: Option<String>, message: String },
+    /// Multipart request (file upload) that cannot be read. `field` is the
+    /// name of the part, if known.
+    InvalidUpload { field: Option<String>, message: String },
+    /// parKVFinder parameters file (`parameters`) that cannot be read.
+    InvalidParameters(String),
+    /// Query string that cannot be read.
+    InvalidQuery(String),
+    /// Input rejected by `Input::check`. Never empty.
+    Invalid(Vec<Violation>),
+    /// Job (tag id) not found in queue.
+    JobNotFound(String),
+    /// Results of a job (tag id) requested before it is completed; the
+    /// second value is the job status.
+    JobNotCompleted(String, String),
+    /// Batch id not found in queue.
+    BatchNotFound(String),
+    /// Queue server failed or could not be reached.
+    Queue(QueueError),
+    /// Job data stored in queue cannot be decompressed.
+    Corrupted(String),
+    /// Unexpected server failure (e.g. compression).
+    Internal(String),
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    code: ErrorCode,
+    message: String,
+    field: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    errors: Option<&'a [Violation]>,
+}
+
+impl KvError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            KvError::InvalidJson { .. } => ErrorCode::InvalidJson,
+            KvError::InvalidUpload { .. } => ErrorCode::InvalidUpload,
+            KvError::InvalidParameters(_) => ErrorCode::InvalidParameters,
+            KvError::InvalidQuery(_) => ErrorCode::InvalidQuery,
+            KvError::Invalid(violations) => violations[0].code,
+            KvError::JobNotFound(_) => ErrorCode::JobNotFound,
+            KvError::JobNotCompleted(..) => ErrorCode::JobNotCompleted,
+            KvError::BatchNotFound(_) => ErrorCode::BatchNotFound,
+            KvError::Queue(e) if is_unavailable(e) => ErrorCode::QueueUnavailable,
+            KvError::Queue(_) => ErrorCode::QueueError,
+            KvError::Corrupted(_) => ErrorCode::CorruptedJobData,
+            KvError::Internal(_) => ErrorCode::InternalError,
+        }
+    }
+
+    /// Error as sent to clients (also used for each failed item of a batch)
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!(ErrorBody {
+            code: self.code(),
+            message: self.to_string(),
+            field: self.field(),
+            errors: match self {
+                KvError::Invalid(violations) => Some(violations),
+                _ => None,
+            },
+        })
+    }
+
+    pub fn field(&self) -> Option<&str> {
+        match self {
+            KvError::InvalidJson { field, .. } | KvError::InvalidUpload { field, .. } => field.as_deref(),
+            KvError::Invalid(violations) => Some(&violations[0].field),
+            KvError::InvalidParameters(_) => Some("parameters"),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for KvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KvError::InvalidJson { message, .. } => write!(f, "Invalid json: {}", message),
+            KvError::InvalidUpload { message, .. } => write!(f, "Invalid upload: {}", message),
+            KvError::InvalidParameters(message) => write!(f, "Invalid parameters file: {}", message),
+            KvError::InvalidQuery(message) => write!(f, "Invalid query string: {}", message),
+            KvError::Invalid(violations) => write!(f, "{}", violations[0].message),
+            KvError::JobNotFound(id) => write!(f, "Job {} not found", id),
+            KvError::JobNotCompleted(id, status) => {
+                write!(f, "Job {} is {}, results are available when it is completed", id, status)
+            }
+            KvError::BatchNotFound(id) => write!(f, "Batch {} not found", id),
+            KvError::Queue(e) if is_unavailable(e) => write!(f, "Job queue unavailable: {}", e),
+            KvError::Queue(e) => write!(f, "Job queue error: {}", e),
+            KvError::Corrupted(e) => write!(f, "Job data is corrupted: {}", e),
+            KvError::Internal(e) => write!(f, "Internal server error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for KvError {}
+
+/// Queue server could not be reached (503), as opposed to a queue server that
+/// answered with an error or an unexpected response (502).
+fn is_unavailable(e: &QueueError) -> bool {
+    match e {
+        QueueError::Request(e) => e.is_connect() || e.is_timeout(),
+        QueueError::UnknownJob(_) => false,
+    }
+}
+
+impl From<QueueError> for KvError {
+    fn from(e: QueueError) -> Self {
+        KvError::Queue(e)
+    }
+}
+
+impl ResponseError for KvError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            KvError::InvalidJson { .. }
+            | KvError::InvalidUpload { .. }
+            | KvError::InvalidParameters(_)
+            | KvError::InvalidQuery(_)
+            | KvError::Invalid(_) => StatusCode::BAD_REQUEST,
+            KvError::JobNotFound(_) | KvError::BatchNotFound(_) => StatusCode::NOT_FOUND,
+            KvError::JobNotCompleted(..) => StatusCode::CONFLICT,
+            KvError::Queue(e) if is_unavailable(e) => StatusCode::SERVICE_UNAVAILABLE,
+            KvError::Queue(_) | KvError::Corrupted(_) => StatusCode::BAD_GATEWAY,
+            KvError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(self.to_json())
+    }
+}
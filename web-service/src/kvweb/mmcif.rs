@@ -0,0 +1,422 @@
+//! Minimal reader of the `_atom_site` category of mmCIF (PDBx) files.
+//! Atoms are converted to the `pdb` module types, so they can be validated and
+//! written as PDB for parKVFinder.
+use super::pdb::{self, error, AltLocFilter, Atom, Model, PdbError};
+
+/// A token of a mmCIF file and the line (starting at 0) where it starts.
+struct Token<'a> {
+    value: &'a str,
+    line: usize,
+    quoted: bool,
+}
+
+impl<'a> Token<'a> {
+    fn is_keyword(&self) -> bool {
+        !self.quoted
+            && (self.value.starts_with('_')
+                || self.value.eq_ignore_ascii_case("loop_")
+                || self.value.starts_with("data_")
+                || self.value.starts_with("save_"))
+    }
+
+    fn is_atom_site(&self) -> bool {
+        !self.quoted && self.value.starts_with("_atom_site.")
+    }
+
+    /// Value or None for the unknown (?) and inapplicable (.) values
+    fn value(&self) -> Option<&'a str> {
+        if !self.quoted && (self.value == "?" || self.value == ".") {
+            None
+        } else {
+            Some(self.value)
+        }
+    }
+}
+
+/// Split mmCIF text into tokens: bare words, quoted strings ('...' or "...")
+/// and multi-line text fields (lines between `;` delimiters). Comments are skipped.
+fn tokenize(text: &str) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+    // first line and start offset of a text field being read
+    let mut text_field: Option<(usize, usize)> = None;
+    let mut offset = 0;
+    for (i, line) in text.split('\n').enumerate() {
+        let line_start = offset;
+        offset += line.len() + 1;
+        if let Some((first, start)) = text_field {
+            if line.starts_with(';') {
+                tokens.push(Token {
+                    value: text[start..line_start].trim_end(),
+                    line: first,
+                    quoted: true,
+                });
+                text_field = None;
+            }
+            continue;
+        }
+        if line.starts_with(';') {
+            text_field = Some((i, line_start + 1));
+            continue;
+        }
+        let bytes = line.as_bytes();
+        let mut j = 0;
+        while j < bytes.len() {
+            let c = bytes[j];
+            if c.is_ascii_whitespace() {
+                j += 1;
+            } else if c == b'#' {
+                break;
+            } else if c == b'\'' || c == b'"' {
+                // a quote closes a string only if followed by whitespace or end of line
+                let mut k = j + 1;
+                while k < bytes.len()
+                    && !(bytes[k] == c && (k + 1 == bytes.len() || bytes[k + 1].is_ascii_whitespace()))
+                {
+                    k += 1;
+                }
+                tokens.push(Token {
+                    value: &line[j + 1..k],
+                    line: i,
+                    quoted: true,
+                });
+                j = k + 1;
+            } else {
+                let mut k = j;
+                while k < bytes.len() && !bytes[k].is_ascii_whitespace() {
+                    k += 1;
+                }
+                tokens.push(Token {
+                    value: &line[j..k],
+                    line: i,
+                    quoted: false,
+                });
+                j = k;
+            }
+        }
+    }
+    tokens
+}
+
+/// `_atom_site` items: column names (without the `_atom_site.` prefix) and rows.
+struct AtomSite<'a> {
+    /// Line (starting at 0) of the first `_atom_site` item
+    line: usize,
+    columns: Vec<&'a str>,
+    rows: Vec<Vec<&'a Token<'a>>>,
+}
+
+impl<'a> AtomSite<'a> {
+    /// Read `_atom_site` as a loop or as key-value pairs (a single atom).
+    /// Returns None if the file has no `_atom_site` items.
+    fn read(tokens: &'a [Token<'a>]) -> Result<Option<AtomSite<'a>>, PdbError> {
+        let start = match tokens.iter().position(|t| t.is_atom_site()) {
+            Some(start) => start,
+            None => return Ok(None),
+        };
+        let is_loop = start > 0
+            && !tokens[start - 1].quoted
+            && tokens[start - 1].value.eq_ignore_ascii_case("loop_");
+        let mut columns = Vec::new();
+        if is_loop {
+            let mut i = start;
+            while i < tokens.len() && tokens[i].is_atom_site() {
+                columns.push(&tokens[i].value["_atom_site.".len()..]);
+                i += 1;
+            }
+            // values end at the next keyword (loop_, data_, _category.item ...)
+            let end = tokens[i..].iter().position(|t| t.is_keyword()).map_or(tokens.len(), |n| i + n);
+            let values = &tokens[i..end];
+            let rows = values.chunks_exact(columns.len());
+            if let Some(last) = rows.remainder().last() {
+                return Err(error(last.line, "Incomplete _atom_site loop row"));
+            }
+            let rows = rows.map(|row| row.iter().collect()).collect();
+            Ok(Some(AtomSite {
+                line: tokens[start].line,
+                columns,
+                rows,
+            }))
+        } else {
+            let mut row = Vec::new();
+            let mut i = start;
+            while i < tokens.len() && tokens[i].is_atom_site() {
+                match tokens.get(i + 1) {
+                    Some(value) if !value.is_keyword() => row.push(value),
+                    _ => return Err(error(tokens[i].line, "Missing _atom_site value")),
+                }
+                columns.push(&tokens[i].value["_atom_site.".len()..]);
+                i += 2;
+            }
+            Ok(Some(AtomSite {
+                line: tokens[start].line,
+                columns,
+                rows: vec![row],
+            }))
+        }
+    }
+
+    /// Index of the first column found in `names`
+    fn column(&self, names: &[&str]) -> Option<usize> {
+        names.iter().find_map(|name| self.columns.iter().position(|c| c == name))
+    }
+}
+
+/// Parse the `_atom_site` records of a mmCIF file grouped by model
+/// (pdbx_PDB_model_num). Author identifiers (auth_*) are preferred over
+/// label_* ones, as they are the ones used in PDB files.
+/// For atoms with alternate locations only the first location is kept.
+/// Line numbers in errors and atoms refer to the mmCIF file.
+pub fn parse(text: &str) -> Result<Vec<Model>, PdbError> {
+    let tokens = tokenize(text);
+    let site = match AtomSite::read(&tokens)? {
+        Some(site) => site,
+        None => return Ok(Vec::new()),
+    };
+    let required = |names: &[&str]| {
+        site.column(names)
+            .ok_or_else(|| error(site.line, &format!("Missing _atom_site.{} item", names[0])))
+    };
+    let group = site.column(&["group_PDB"]);
+    let name = required(&["auth_atom_id", "label_atom_id"])?;
+    let alt_loc = site.column(&["label_alt_id", "auth_alt_id"]);
+    let res_name = site.column(&["auth_comp_id", "label_comp_id"]);
+    let chain_id = site.column(&["auth_asym_id", "label_asym_id"]);
+    let res_seq = site.column(&["auth_seq_id", "label_seq_id"]);
+    let i_code = site.column(&["pdbx_PDB_ins_code"]);
+    let x = required(&["Cartn_x"])?;
+    let y = required(&["Cartn_y"])?;
+    let z = required(&["Cartn_z"])?;
+    let occupancy = site.column(&["occupancy"]);
+    let temp_factor = site.column(&["B_iso_or_equiv"]);
+    let element = site.column(&["type_symbol"]);
+    let model_num = site.column(&["pdbx_PDB_model_num"]);
+
+    let mut models: Vec<(Model, AltLocFilter)> = Vec::new();
+    for row in &site.rows {
+        let line = row[0].line;
+        let value = |column: Option<usize>| column.and_then(|c| row[c].value());
+        let text = |column: Option<usize>| value(column).unwrap_or("").to_string();
+        let char_of = |column: Option<usize>| {
+            value(column).and_then(|v| v.chars().next()).unwrap_or(' ')
+        };
+        let coord = |column: usize| {
+            value(Some(column))
+                .and_then(|v| v.parse::<f64>().ok())
+                .ok_or_else(|| error(line, "Cannot read atom coordinates"))
+        };
+        let serial = match value(model_num) {
+            Some(v) => v
+                .parse::<u32>()
+                .map_err(|_| error(line, "Invalid pdbx_PDB_model_num"))?,
+            None => 1,
+        };
+        let atom = Atom {
+            hetero: value(group) == Some("HETATM"),
+            name: text(Some(name)),
+            alt_loc: char_of(alt_loc),
+            res_name: text(res_name),
+            chain_id: text(chain_id),
+            res_seq: match value(res_seq) {
+                Some(v) => v
+                    .parse::<i32>()
+                    .map_err(|_| error(line, "Cannot read residue sequence number"))?,
+                None => 0,
+            },
+            i_code: char_of(i_code),
+            x: coord(x)?,
+            y: coord(y)?,
+            z: coord(z)?,
+            occupancy: value(occupancy).and_then(|v| v.parse().ok()).unwrap_or(1.0),
+            temp_factor: value(temp_factor).and_then(|v| v.parse().ok()).unwrap_or(0.0),
+            element: text(element),
+            line: line + 1,
+        };
+        let index = match models.iter().position(|(m, _)| m.serial == serial) {
+            Some(index) => index,
+            None => {
+                models.push((
+                    Model {
+                        serial,
+                        atoms: Vec::new(),
+                    },
+                    AltLocFilter::default(),
+                ));
+                models.len() - 1
+            }
+        };
+        let (model, alt_locs) = &mut models[index];
+        if alt_locs.keep(&atom) {
+            model.atoms.push(atom);
+        }
+    }
+    Ok(models.into_iter().map(|(model, _)| model).collect())
+}
+
+/// Convert one model (the first one if `serial` is None) of a mmCIF file to
+/// PDB ATOM/HETATM records (see `pdb::to_pdb`). Other models are skipped, so
+/// values that do not fit PDB columns are only reported for the chosen one.
+pub fn to_pdb(text: &str, serial: Option<u32>) -> Result<String, PdbError> {
+    let models = parse(text)?;
+    let model = pdb::find_model(&models, serial);
+    pdb::to_pdb(model.map_or(&[], std::slice::from_ref))
+}
+
+/// Guess if a structure is a mmCIF file: its first data line is a data block
+/// header (`data_...`) or it has `_atom_site` items.
+pub fn is_mmcif(text: &str) -> bool {
+    text.lines()
+        .map(str::trim_start)
+        .find(|l| !l.is_empty() && !l.starts_with('#'))
+        .is_some_and(|l| l.starts_with("data_"))
+        || text.contains("\n_atom_site.")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CIF_1FMO: &str = include_str!("../../../examples/1FMO.cif");
+    const PDB_1FMO: &str = include_str!("../../../examples/1FMO.pdb");
+    const CIF_1HVR: &str = include_str!("../../../examples/1HVR.cif");
+    const PDB_1HVR: &str = include_str!("../../../examples/1HVR.pdb");
+
+    /// mmCIF and PDB files of the same structures, with their number of atoms
+    /// (1FMO has hetero atoms, 1HVR two chains and hydrogens)
+    const STRUCTURES: [(&str, &str, usize); 2] = [(CIF_1FMO, PDB_1FMO, 2792), (CIF_1HVR, PDB_1HVR, 3120)];
+
+    /// Atoms without their line numbers, which refer to different files
+    fn atoms(model: &Model) -> Vec<Atom> {
+        model.atoms.iter().map(|a| Atom { line: 0, ..a.clone() }).collect()
+    }
+
+    #[test]
+    fn cif_and_pdb_files_have_the_same_atoms() {
+        for (cif_text, pdb_text, count) in STRUCTURES {
+            let cif = parse(cif_text).unwrap();
+            let pdb = pdb::parse(pdb_text).unwrap();
+            assert_eq!(cif.len(), 1);
+            assert_eq!(pdb.len(), 1);
+            assert_eq!(cif[0].serial, pdb[0].serial);
+            assert_eq!(atoms(&cif[0]), atoms(&pdb[0]));
+            assert_eq!(cif[0].stats(), pdb[0].stats());
+            assert_eq!(cif[0].stats().atoms, count);
+        }
+        assert_eq!(parse(CIF_1HVR).unwrap()[0].stats().chains, 2);
+    }
+
+    #[test]
+    fn converted_files_are_read_back_as_the_same_atoms() {
+        for (cif_text, _, _) in STRUCTURES {
+            let converted = to_pdb(cif_text, None).unwrap();
+            let models = pdb::parse(&converted).unwrap();
+            assert_eq!(models.len(), 1);
+            assert_eq!(atoms(&models[0]), atoms(&parse(cif_text).unwrap()[0]));
+        }
+        assert_eq!(
+            to_pdb(CIF_1FMO, None).unwrap().lines().next(),
+            Some("ATOM      1  N   GLU E  13      -6.693 -15.642 -14.858  1.00100.00           N")
+        );
+    }
+
+    #[test]
+    fn quoted_values_and_text_fields_are_read() {
+        let text = "data_TEST
+_struct.title
+;A title with _atom_site.id and loop_
+ATOM 1 C C1 . A X 1 ? 1 2 3 1 0 1 A X C1 1
+;
+_struct.pdbx_descriptor 'Nucleic acid, \"DNA\"'
+loop_
+_atom_site.group_PDB
+_atom_site.type_symbol
+_atom_site.auth_atom_id
+_atom_site.auth_comp_id
+_atom_site.auth_asym_id
+_atom_site.auth_seq_id
+_atom_site.Cartn_x
+_atom_site.Cartn_y
+_atom_site.Cartn_z
+ATOM C \"C1'\" DA A 1 1.5 2.5 3.5
+ATOM O 'O5'' DA A 1 -1.0 0.0 10.25 # comment
+HETATM O O HOH . ? 4 5 6
+#
+";
+        let models = parse(text).unwrap();
+        assert_eq!(models.len(), 1);
+        let atoms = &models[0].atoms;
+        let names: Vec<&str> = atoms.iter().map(|a| a.name.as_str()).collect();
+        assert_eq!(names, ["C1'", "O5'", "O"]);
+        assert_eq!((atoms[1].x, atoms[1].y, atoms[1].z), (-1.0, 0.0, 10.25));
+        assert_eq!(atoms[1].line, 18);
+        assert!(atoms[2].hetero);
+        assert_eq!(atoms[2].chain_id, "");
+        assert_eq!(atoms[2].res_seq, 0);
+        assert_eq!(
+            to_pdb(text, None).unwrap().lines().nth(1),
+            Some("ATOM      2  O5'  DA A   1      -1.000   0.000  10.250  1.00  0.00           O")
+        );
+    }
+
+    const MODELS: &str = "data_TEST
+loop_
+_atom_site.group_PDB
+_atom_site.type_symbol
+_atom_site.label_atom_id
+_atom_site.label_comp_id
+_atom_site.label_asym_id
+_atom_site.label_seq_id
+_atom_site.Cartn_x
+_atom_site.Cartn_y
+_atom_site.Cartn_z
+_atom_site.auth_seq_id
+_atom_site.auth_asym_id
+_atom_site.pdbx_PDB_model_num
+ATOM N N GLY A 1 0.0 0.0 0.0 7 B 1
+ATOM C CA GLY A 1 1.0 0.0 0.0 7 B 1
+ATOM N N GLY A 1 0.0 1.0 0.0 7 B 2
+ATOM C CA GLY A 1 1.0 1.0 0.0 7 B 2
+";
+
+    #[test]
+    fn multi_model_atom_site_is_split_by_model_number() {
+        let models = parse(MODELS).unwrap();
+        let serials: Vec<u32> = models.iter().map(|m| m.serial).collect();
+        assert_eq!(serials, [1, 2]);
+        assert!(models.iter().all(|m| m.atoms.len() == 2));
+        // auth_* identifiers are preferred over label_* ones
+        assert_eq!((models[1].atoms[0].res_seq, models[1].atoms[0].chain_id.as_str()), (7, "B"));
+
+        // only the chosen model is converted
+        let converted = pdb::parse(&to_pdb(MODELS, Some(2)).unwrap()).unwrap();
+        assert_eq!(converted.len(), 1);
+        assert_eq!(atoms(&converted[0]), atoms(&models[1]));
+
+        // every model is written with MODEL records and read back
+        let written = pdb::to_pdb(&models).unwrap();
+        assert!(written.starts_with("MODEL        1\n"));
+        let read = pdb::parse(&written).unwrap();
+        assert_eq!(read.iter().map(|m| m.serial).collect::<Vec<u32>>(), [1, 2]);
+        assert_eq!(atoms(&read[1]), atoms(&models[1]));
+    }
+
+    #[test]
+    fn values_that_do_not_fit_pdb_columns_are_errors() {
+        let cases = [
+            ("7 B 2", "10000 B 2", "Residue sequence number 10000 does not fit in PDB columns 23-26 at line 17"),
+            ("7 B 2", "-1000 B 2", "Residue sequence number -1000 does not fit in PDB columns 23-26 at line 17"),
+            ("7 B 2", "7 BA 2", "Chain identifier BA does not fit in PDB columns 22 at line 17"),
+            ("1.0 1.0 0.0", "1.0 100000.0 0.0", "Y coordinate 100000.000 does not fit in PDB columns 39-46 at line 18"),
+        ];
+        for (from, to, message) in cases {
+            let text = MODELS.replacen(from, to, 1);
+            assert_eq!(to_pdb(&text, Some(2)).unwrap_err().to_string(), message);
+            // other models are not converted
+            assert!(to_pdb(&text, Some(1)).is_ok());
+        }
+        // -999 and 9999 fit in 4 columns
+        let text = MODELS.replacen("7 B 1", "-999 B 1", 1).replacen("7 B 1", "9999 B 1", 1);
+        let converted = pdb::parse(&to_pdb(&text, None).unwrap()).unwrap();
+        let res_seqs: Vec<i32> = converted[0].atoms.iter().map(|a| a.res_seq).collect();
+        assert_eq!(res_seqs, [-999, 9999]);
+    }
+}
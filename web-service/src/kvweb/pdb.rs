@@ -0,0 +1,377 @@
+//! Minimal parser of PDB coordinate records (ATOM, HETATM, MODEL and ENDMDL).
+//! Columns follow the PDB format v3.3 specification.
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Atom {
+    /// HETATM record
+    pub hetero: bool,
+    pub name: String,
+    pub alt_loc: char,
+    pub res_name: String,
+    /// Chain identifier (may have more than one character in mmCIF files)
+    pub chain_id: String,
+    pub res_seq: i32,
+    pub i_code: char,
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+    pub occupancy: f64,
+    pub temp_factor: f64,
+    pub element: String,
+    /// Line number (starting at 1) in the PDB file
+    pub line: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Model {
+    /// MODEL serial number (1 if the file has no MODEL records)
+    pub serial: u32,
+    pub atoms: Vec<Atom>,
+}
+
+/// Residue names of water molecules
+const WATERS: [&str; 5] = ["HOH", "WAT", "H2O", "DOD", "D2O"];
+
+impl Atom {
+    pub fn is_water(&self) -> bool {
+        WATERS.contains(&self.res_name.as_str())
+    }
+}
+
+/// Number of atoms, residues and chains of a model
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Stats {
+    pub atoms: usize,
+    pub residues: usize,
+    pub chains: usize,
+}
+
+impl Model {
+    pub fn stats(&self) -> Stats {
+        let residues: HashSet<(&str, i32, char)> = self
+            .atoms
+            .iter()
+            .map(|a| (a.chain_id.as_str(), a.res_seq, a.i_code))
+            .collect();
+        let chains: HashSet<&str> = self.atoms.iter().map(|a| a.chain_id.as_str()).collect();
+        Stats {
+            atoms: self.atoms.len(),
+            residues: residues.len(),
+            chains: chains.len(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PdbError {
+    /// Line number (starting at 1) in the PDB file
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for PdbError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at line {}", self.message, self.line)
+    }
+}
+
+impl std::error::Error for PdbError {}
+
+/// Keeps only the first alternate location of each atom of a model.
+#[derive(Default)]
+pub(super) struct AltLocFilter {
+    // atoms (chain, residue, insertion code, name) with alternate location already read
+    seen: HashSet<(String, i32, char, String)>,
+}
+
+impl AltLocFilter {
+    pub(super) fn keep(&mut self, atom: &Atom) -> bool {
+        atom.alt_loc == ' '
+            || self.seen.insert((
+                atom.chain_id.clone(),
+                atom.res_seq,
+                atom.i_code,
+                atom.name.clone(),
+            ))
+    }
+
+    pub(super) fn clear(&mut self) {
+        self.seen.clear();
+    }
+}
+
+/// Parse ATOM/HETATM records grouped by model. Atoms outside MODEL/ENDMDL
+/// blocks belong to model 1 (or to the last model read).
+/// For atoms with alternate locations only the first location is kept.
+pub fn parse(text: &str) -> Result<Vec<Model>, PdbError> {
+    let mut models: Vec<Model> = Vec::new();
+    let mut alt_locs = AltLocFilter::default();
+    for (i, line) in text.lines().enumerate() {
+        let record = line.get(0..6).unwrap_or(line).trim_end();
+        match record {
+            "MODEL" => {
+                let serial = line
+                    .get(6..)
+                    .unwrap_or("")
+                    .trim()
+                    .parse::<u32>()
+                    .map_err(|_| error(i, "Invalid MODEL serial number"))?;
+                models.push(Model {
+                    serial,
+                    atoms: Vec::new(),
+                });
+                alt_locs.clear();
+            }
+            "ATOM" | "HETATM" => {
+                let atom = parse_atom(line, i)?;
+                if !alt_locs.keep(&atom) {
+                    continue;
+                }
+                if models.is_empty() {
+                    models.push(Model {
+                        serial: 1,
+                        atoms: Vec::new(),
+                    });
+                }
+                if let Some(model) = models.last_mut() {
+                    model.atoms.push(atom);
+                }
+            }
+            _ => (),
+        }
+    }
+    Ok(models)
+}
+
+/// Get a model by its serial number or the first model if `serial` is None.
+pub fn find_model(models: &[Model], serial: Option<u32>) -> Option<&Model> {
+    match serial {
+        Some(serial) => models.iter().find(|m| m.serial == serial),
+        None => models.first(),
+    }
+}
+
+/// Keep only the records of one model (the first one if `serial` is None).
+/// Records outside MODEL/ENDMDL blocks are kept. Text without MODEL records is
+/// returned unchanged.
+pub fn select_model(text: &str, serial: Option<u32>) -> String {
+    let mut selected = String::with_capacity(text.len());
+    // None outside a MODEL block, otherwise if the current model is kept
+    let mut keep: Option<bool> = None;
+    let mut found = false;
+    for line in text.lines() {
+        let record = line.get(0..6).unwrap_or(line).trim_end();
+        match record {
+            "MODEL" => {
+                let current = line.get(6..).unwrap_or("").trim().parse::<u32>().ok();
+                let wanted = !found
+                    && match serial {
+                        Some(s) => current == Some(s),
+                        None => true,
+                    };
+                found |= wanted;
+                keep = Some(wanted);
+            }
+            "ENDMDL" => {
+                let kept = keep.unwrap_or(true);
+                keep = None;
+                if !kept {
+                    continue;
+                }
+            }
+            _ => (),
+        }
+        if keep.unwrap_or(true) {
+            selected.push_str(line);
+            selected.push('\n');
+        }
+    }
+    selected
+}
+
+/// `value` right-aligned in a PDB field of `width` characters (columns
+/// `columns`), or an error at `line` if it does not fit.
+fn field(value: &str, width: usize, name: &str, columns: &str, line: usize) -> Result<String, PdbError> {
+    if value.chars().count() > width {
+        return Err(PdbError {
+            line,
+            message: format!("{} {} does not fit in PDB columns {}", name, value, columns),
+        });
+    }
+    Ok(format!("{:>width$}", value, width = width))
+}
+
+/// Write models as PDB ATOM/HETATM records. MODEL/ENDMDL records are written
+/// only if there is more than one model. Atom serial numbers restart after
+/// 99999; any other value that does not fit its PDB columns (e.g. residue
+/// numbers above 9999 or chain identifiers with more than one character,
+/// found in large mmCIF files) is an error at the line of the atom.
+pub fn to_pdb(models: &[Model]) -> Result<String, PdbError> {
+    let mut text = String::new();
+    for model in models {
+        if models.len() > 1 {
+            let line = model.atoms.first().map_or(0, |a| a.line);
+            let serial = field(&model.serial.to_string(), 4, "MODEL serial number", "11-14", line)?;
+            text.push_str(&format!("MODEL     {}\n", serial));
+        }
+        for (i, atom) in model.atoms.iter().enumerate() {
+            let fit = |value: &str, width, name, columns| field(value, width, name, columns, atom.line);
+            fit(&atom.name, 4, "Atom name", "13-16")?;
+            // atom names shorter than 4 characters start at column 14 (e.g. " CA ")
+            let name = if atom.name.len() < 4 && atom.element.len() < 2 {
+                format!(" {:<3}", atom.name)
+            } else {
+                format!("{:<4}", atom.name)
+            };
+            text.push_str(&format!(
+                "{:<6}{:>5} {}{}{} {}{}{}   {}{}{}{}{}          {}\n",
+                if atom.hetero { "HETATM" } else { "ATOM" },
+                (i + 1) % 100_000,
+                name,
+                atom.alt_loc,
+                fit(&atom.res_name, 3, "Residue name", "18-20")?,
+                fit(&atom.chain_id, 1, "Chain identifier", "22")?,
+                fit(&atom.res_seq.to_string(), 4, "Residue sequence number", "23-26")?,
+                atom.i_code,
+                fit(&format!("{:.3}", atom.x), 8, "X coordinate", "31-38")?,
+                fit(&format!("{:.3}", atom.y), 8, "Y coordinate", "39-46")?,
+                fit(&format!("{:.3}", atom.z), 8, "Z coordinate", "47-54")?,
+                fit(&format!("{:.2}", atom.occupancy), 6, "Occupancy", "55-60")?,
+                fit(&format!("{:.2}", atom.temp_factor), 6, "Temperature factor", "61-66")?,
+                fit(&atom.element, 2, "Element symbol", "77-78")?,
+            ));
+        }
+        if models.len() > 1 {
+            text.push_str("ENDMDL\n");
+        }
+    }
+    text.push_str("END\n");
+    Ok(text)
+}
+
+fn parse_atom(line: &str, i: usize) -> Result<Atom, PdbError> {
+    let column = |range: std::ops::Range<usize>| line.get(range).unwrap_or("");
+    let char_at = |n: usize| column(n..n + 1).chars().next().unwrap_or(' ');
+    let coord = |range| {
+        column(range)
+            .trim()
+            .parse::<f64>()
+            .map_err(|_| error(i, "Cannot read atom coordinates"))
+    };
+    Ok(Atom {
+        hetero: line.starts_with("HETATM"),
+        name: column(12..16).trim().to_string(),
+        alt_loc: char_at(16),
+        res_name: column(17..20).trim().to_string(),
+        chain_id: column(21..22).trim().to_string(),
+        res_seq: column(22..26)
+            .trim()
+            .parse::<i32>()
+            .map_err(|_| error(i, "Cannot read residue sequence number"))?,
+        i_code: char_at(26),
+        x: coord(30..38)?,
+        y: coord(38..46)?,
+        z: coord(46..54)?,
+        occupancy: column(54..60).trim().parse().unwrap_or(1.0),
+        temp_factor: column(60..66).trim().parse().unwrap_or(0.0),
+        element: column(76..78).trim().to_string(),
+        line: i + 1,
+    })
+}
+
+pub(super) fn error(i: usize, message: &str) -> PdbError {
+    PdbError {
+        line: i + 1,
+        message: message.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kvweb::PdbBoundaries;
+
+    const MODELS: &str = "\
+HEADER    TEST STRUCTURE
+MODEL        1
+ATOM      1  N   GLY A   1       1.000   2.000   3.000  1.00  0.00           N
+ATOM      2  CA BGLY A   1       2.000   2.000   3.000  0.40  0.00           C
+ATOM      3  CA AGLY A   1       2.500   2.000   3.000  0.60  0.00           C
+HETATM    4  C1  LIG A 101      10.000  -5.000   3.000  1.00  0.00           C
+ENDMDL
+MODEL        2
+ATOM      1  N   GLY A   1       1.100   2.000   3.000  1.00  0.00           N
+ATOM      2  CA AGLY A   1       2.100   2.000   3.000  0.60  0.00           C
+ENDMDL
+HETATM    5  O   HOH A 201       0.000   0.000   0.000  1.00  0.00           O
+CONECT    1    2
+END
+";
+
+    /// Lines of `MODELS` (numbered from 1)
+    fn lines(numbers: &[usize]) -> String {
+        let lines: Vec<&str> = MODELS.lines().collect();
+        numbers.iter().map(|n| format!("{}\n", lines[n - 1])).collect()
+    }
+
+    #[test]
+    fn atoms_are_grouped_by_model() {
+        let models = parse(MODELS).unwrap();
+        assert_eq!(models.iter().map(|m| m.serial).collect::<Vec<_>>(), [1, 2]);
+        let names: Vec<&str> = models[0].atoms.iter().map(|a| a.name.as_str()).collect();
+        assert_eq!(names, ["N", "CA", "C1"]);
+        assert!(models[0].atoms[2].hetero);
+        assert_eq!(models[0].atoms[2].line, 6);
+        assert_eq!(find_model(&models, None), Some(&models[0]));
+        assert_eq!(find_model(&models, Some(2)), Some(&models[1]));
+        assert_eq!(find_model(&models, Some(3)), None);
+    }
+
+    #[test]
+    fn atoms_outside_model_blocks_belong_to_the_last_model() {
+        let models = parse(MODELS).unwrap();
+        let water = models[1].atoms.last().unwrap();
+        assert_eq!((water.res_name.as_str(), water.line), ("HOH", 12));
+        assert!(water.is_water());
+        // without MODEL records, atoms belong to model 1
+        let models = parse(&lines(&[1, 3, 6, 14])).unwrap();
+        assert_eq!(models.len(), 1);
+        assert_eq!(models[0].serial, 1);
+        assert_eq!(models[0].atoms.len(), 2);
+    }
+
+    #[test]
+    fn only_the_first_alternate_location_is_kept() {
+        let models = parse(MODELS).unwrap();
+        let ca: Vec<(char, f64)> = models
+            .iter()
+            .flat_map(|m| m.atoms.iter())
+            .filter(|a| a.name == "CA")
+            .map(|a| (a.alt_loc, a.x))
+            .collect();
+        // location B comes first in model 1, A is the only one in model 2
+        assert_eq!(ca, [('B', 2.0), ('A', 2.1)]);
+    }
+
+    #[test]
+    fn select_model_keeps_records_outside_model_blocks() {
+        assert_eq!(select_model(MODELS, None), lines(&[1, 2, 3, 4, 5, 6, 7, 12, 13, 14]));
+        assert_eq!(select_model(MODELS, Some(1)), select_model(MODELS, None));
+        assert_eq!(select_model(MODELS, Some(2)), lines(&[1, 8, 9, 10, 11, 12, 13, 14]));
+        assert_eq!(select_model(MODELS, Some(3)), lines(&[1, 12, 13, 14]));
+        let single = lines(&[1, 3, 6, 14]);
+        assert_eq!(select_model(&single, None), single);
+    }
+
+    #[test]
+    fn hetero_atoms_are_within_the_boundaries() {
+        let models = parse(MODELS).unwrap();
+        let b = PdbBoundaries::of(&models[0].atoms, 1.0);
+        assert_eq!((b.x_min, b.x_max), (0.0, 11.0));
+        assert_eq!((b.y_min, b.y_max), (-6.0, 3.0));
+        assert_eq!((b.z_min, b.z_max), (2.0, 4.0));
+    }
+}
@@ -0,0 +1,539 @@
+use super::worker::JobInput;
+use super::{Data, Failure, Input, Output};
+use async_trait::async_trait;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+/// Job data without input. The output is stored compressed (see `compress`),
+/// exactly as it was sent to the queue.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct JobRecord {
+    pub status: String,
+    pub output: Option<JobResult>,
+    pub created_at: String,
+    pub started_at: Option<String>,
+    pub ended_at: Option<String>,
+    pub expires_after: String,
+}
+
+/// Output of a finished job: the results of a completed job or, stored as
+/// `{"failure": {...}}`, why a job failed.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum JobResult {
+    Completed(Output),
+    Failed { failure: Failure },
+}
+
+/// Input of a job, stored compressed as it was sent to the queue.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct InputRecord {
+    pub input: Input,
+    pub created_at: String,
+}
+
+/// Job data without input and output, for status polling.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct JobSummary {
+    pub status: String,
+    pub created_at: String,
+    pub started_at: Option<String>,
+    pub ended_at: Option<String>,
+    pub expires_after: String,
+}
+
+#[derive(Debug)]
+pub enum QueueError {
+    /// Request to the queue server failed (connection, HTTP status or body).
+    Request(reqwest::Error),
+    /// Queue id does not match any job.
+    UnknownJob(u32),
+}
+
+impl fmt::Display for QueueError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QueueError::Request(e) => write!(f, "queue request failed: {}", e),
+            QueueError::UnknownJob(id) => write!(f, "job {} not found in queue", id),
+        }
+    }
+}
+
+impl Error for QueueError {}
+
+impl From<reqwest::Error> for QueueError {
+    fn from(e: reqwest::Error) -> Self {
+        QueueError::Request(e)
+    }
+}
+
+/// Operations the webserver and the worker need from a job queue.
+/// Jobs have two ids: the queue id, assigned sequentially by the queue, and
+/// the tag id, a hash of the input sent to users (see `webserver::create`).
+/// Methods are async so a slow queue server does not block actix workers.
+#[async_trait]
+pub trait JobQueue: Send + Sync {
+    /// Add a job to the queue and return its queue id.
+    async fn enqueue(&self, data: &Data) -> Result<u32, QueueError>;
+
+    /// Get queue id of the newest job with this tag. Returns Ok(None) if not found.
+    async fn find_by_tag(&self, tag: &str) -> Result<Option<u32>, QueueError>;
+
+    /// Get job data without input. Returns Ok(None) if not found.
+    async fn get(&self, id: u32) -> Result<Option<JobRecord>, QueueError>;
+
+    /// Get job input. Returns Ok(None) if not found.
+    async fn get_input(&self, id: u32) -> Result<Option<InputRecord>, QueueError>;
+
+    /// Get job status only. Returns Ok(None) if not found.
+    async fn status(&self, id: u32) -> Result<Option<String>, QueueError>;
+
+    /// Get job data without input and output. Returns Ok(None) if not found.
+    async fn summary(&self, id: u32) -> Result<Option<JobSummary>, QueueError>;
+
+    /// Take the next queued job (status changes to "running").
+    /// Returns Ok(None) if there is not a job to process.
+    async fn next_job(&self) -> Result<Option<JobInput>, QueueError>;
+
+    /// Store job output and mark it as "completed".
+    async fn complete(&self, id: u32, output: Output) -> Result<(), QueueError>;
+
+    /// Store why a job failed and mark it as "failed".
+    async fn fail(&self, id: u32, failure: Failure) -> Result<(), QueueError>;
+
+    /// Number of jobs waiting to be processed.
+    async fn size(&self) -> Result<usize, QueueError>;
+
+    /// Keep the tag ids of the jobs of a batch (see `webserver::batch`).
+    /// Batches expire like finished jobs.
+    async fn save_batch(&self, batch_id: &str, tags: &[String]) -> Result<(), QueueError>;
+
+    /// Get the tag ids of the jobs of a batch. Returns Ok(None) if not found.
+    async fn find_batch(&self, batch_id: &str) -> Result<Option<Vec<String>>, QueueError>;
+}
+
+#[derive(Serialize)]
+struct QueueConfig<'a> {
+    timeout: &'a str,
+    expires_after: &'a str,
+    retries: i32,
+}
+
+/// Batch kept as an Ocypod job (input) of the batches queue
+#[derive(Serialize, Deserialize)]
+struct BatchRecord {
+    jobs: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct JobUpdate<'a> {
+    status: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    output: Option<JobResult>,
+}
+
+/// Client for an Ocypod (https://github.com/davechallis/ocypod) queue server.
+/// Batches are kept as jobs of a second queue, "<queue_name>-batches", which
+/// is never processed.
+pub struct OcypodQueue {
+    client: reqwest::Client,
+    url: String,
+    queue_name: String,
+}
+
+impl OcypodQueue {
+    /// `url` is the Ocypod server address, e.g. "http://ocypod:8023".
+    pub fn new(url: &str, queue_name: &str) -> OcypodQueue {
+        OcypodQueue {
+            client: reqwest::Client::new(),
+            url: url.trim_end_matches('/').to_string(),
+            queue_name: queue_name.to_string(),
+        }
+    }
+
+    /// Create (or update) the queues (jobs and batches) on Ocypod server.
+    pub async fn create_queue(&self, timeout: &str, expires_after: &str, retries: i32) -> Result<(), QueueError> {
+        let queue_config = QueueConfig {
+            timeout,
+            expires_after,
+            retries,
+        };
+        for name in [self.queue_name.clone(), self.batch_queue_name()] {
+            self.client
+                .put(format!("{}/queue/{}", self.url, name))
+                .json(&queue_config)
+                .send()
+                .await?
+                .error_for_status()?;
+        }
+        Ok(())
+    }
+
+    fn batch_queue_name(&self) -> String {
+        format!("{}-batches", self.queue_name)
+    }
+
+    async fn update(&self, id: u32, update: &JobUpdate<'_>) -> Result<(), QueueError> {
+        let response = self
+            .client
+            .patch(format!("{}/job/{}", self.url, id))
+            .json(update)
+            .send()
+            .await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Err(QueueError::UnknownJob(id));
+        }
+        response.error_for_status()?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl JobQueue for OcypodQueue {
+    async fn enqueue(&self, data: &Data) -> Result<u32, QueueError> {
+        let id = self
+            .client
+            .post(format!("{}/queue/{}/job", self.url, self.queue_name))
+            .json(data)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(id)
+    }
+
+    async fn find_by_tag(&self, tag: &str) -> Result<Option<u32>, QueueError> {
+        // more than one job has the same tag when a failed job is submitted again
+        let ids: Vec<u32> = self
+            .client
+            .get(format!("{}/tag/{}", self.url, tag))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        // the newest job (the highest id) or None
+        Ok(ids.into_iter().max())
+    }
+
+    async fn get(&self, id: u32) -> Result<Option<JobRecord>, QueueError> {
+        let response = self
+            .client
+            .get(format!(
+                "{}/job/{}?fields=status,output,created_at,started_at,ended_at,expires_after",
+                self.url, id
+            ))
+            .send()
+            .await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        Ok(Some(response.error_for_status()?.json().await?))
+    }
+
+    async fn get_input(&self, id: u32) -> Result<Option<InputRecord>, QueueError> {
+        let response = self
+            .client
+            .get(format!("{}/job/{}?fields=input,created_at", self.url, id))
+            .send()
+            .await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        Ok(Some(response.error_for_status()?.json().await?))
+    }
+
+    async fn status(&self, id: u32) -> Result<Option<String>, QueueError> {
+        let response = self.client.get(format!("{}/job/{}/status", self.url, id)).send().await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        Ok(Some(response.error_for_status()?.json().await?))
+    }
+
+    async fn summary(&self, id: u32) -> Result<Option<JobSummary>, QueueError> {
+        let response = self
+            .client
+            .get(format!(
+                "{}/job/{}?fields=status,created_at,started_at,ended_at,expires_after",
+                self.url, id
+            ))
+            .send()
+            .await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        Ok(Some(response.error_for_status()?.json().await?))
+    }
+
+    async fn next_job(&self) -> Result<Option<JobInput>, QueueError> {
+        let response = self
+            .client
+            .get(format!("{}/queue/{}/job", self.url, self.queue_name))
+            .send()
+            .await?
+            .error_for_status()?;
+        // ocypod answers 204 (no content) when the queue is empty
+        if response.status() == StatusCode::NO_CONTENT {
+            return Ok(None);
+        }
+        Ok(Some(response.json().await?))
+    }
+
+    async fn complete(&self, id: u32, output: Output) -> Result<(), QueueError> {
+        self.update(
+            id,
+            &JobUpdate {
+                status: "completed",
+                output: Some(JobResult::Completed(output)),
+            },
+        )
+        .await
+    }
+
+    async fn fail(&self, id: u32, failure: Failure) -> Result<(), QueueError> {
+        self.update(
+            id,
+            &JobUpdate {
+                status: "failed",
+                output: Some(JobResult::Failed { failure }),
+            },
+        )
+        .await
+    }
+
+    async fn size(&self) -> Result<usize, QueueError> {
+        let size = self
+            .client
+            .get(format!("{}/queue/{}/size", self.url, self.queue_name))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(size)
+    }
+
+    async fn save_batch(&self, batch_id: &str, tags: &[String]) -> Result<(), QueueError> {
+        let id: u32 = self
+            .client
+            .post(format!("{}/queue/{}/job", self.url, self.batch_queue_name()))
+            .json(&serde_json::json!({
+                "input": BatchRecord { jobs: tags.to_vec() },
+                "tags": [batch_id],
+            }))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        // a cancelled job is finished, so ocypod removes it after expires_after
+        self.update(
+            id,
+            &JobUpdate {
+                status: "cancelled",
+                output: None,
+            },
+        )
+        .await
+    }
+
+    async fn find_batch(&self, batch_id: &str) -> Result<Option<Vec<String>>, QueueError> {
+        let id = match self.find_by_tag(batch_id).await? {
+            Some(id) => id,
+            None => return Ok(None),
+        };
+        #[derive(Deserialize)]
+        struct Fields {
+            input: BatchRecord,
+        }
+        let response = self
+            .client
+            .get(format!("{}/job/{}?fields=input", self.url, id))
+            .send()
+            .await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let fields: Fields = response.error_for_status()?.json().await?;
+        Ok(Some(fields.input.jobs))
+    }
+}
+
+struct MemoryJob {
+    tags: Vec<String>,
+    input: Input,
+    record: JobRecord,
+    ended: Option<SystemTime>,
+}
+
+#[derive(Default)]
+struct MemoryState {
+    last_id: u32,
+    jobs: HashMap<u32, MemoryJob>,
+    queued: VecDeque<u32>,
+    /// tag ids of the jobs of each batch and when the batch was saved
+    batches: HashMap<String, (Vec<String>, SystemTime)>,
+}
+
+impl MemoryState {
+    /// Drop finished jobs and batches older than `expires_after`.
+    fn expire(&mut self, expires_after: Duration) {
+        self.jobs.retain(|_, job| match job.ended {
+            Some(ended) => ended.elapsed().map(|e| e < expires_after).unwrap_or(true),
+            None => true,
+        });
+        self.batches
+            .retain(|_, (_, saved)| saved.elapsed().map(|e| e < expires_after).unwrap_or(true));
+    }
+}
+
+/// In-process queue. Jobs live only while the process runs, so the webserver
+/// and the worker must share the same `MemoryQueue` (e.g. through an `Arc`).
+/// Useful to run and test the service without Ocypod/Redis.
+pub struct MemoryQueue {
+    state: Mutex<MemoryState>,
+    expires_after: Duration,
+}
+
+impl MemoryQueue {
+    /// Finished jobs (completed or failed) are removed after `expires_after`.
+    pub fn new(expires_after: Duration) -> MemoryQueue {
+        MemoryQueue {
+            state: Mutex::new(MemoryState::default()),
+            expires_after,
+        }
+    }
+
+    fn finish(&self, id: u32, status: &str, output: JobResult) -> Result<(), QueueError> {
+        let mut state = self.state.lock().unwrap();
+        let job = state.jobs.get_mut(&id).ok_or(QueueError::UnknownJob(id))?;
+        job.record.status = status.to_string();
+        job.record.output = Some(output);
+        job.record.ended_at = Some(now());
+        job.ended = Some(SystemTime::now());
+        Ok(())
+    }
+}
+
+fn now() -> String {
+    humantime::format_rfc3339_nanos(SystemTime::now()).to_string()
+}
+
+#[async_trait]
+impl JobQueue for MemoryQueue {
+    async fn enqueue(&self, data: &Data) -> Result<u32, QueueError> {
+        let mut state = self.state.lock().unwrap();
+        state.expire(self.expires_after);
+        state.last_id += 1;
+        let id = state.last_id;
+        let job = MemoryJob {
+            tags: data.tags.to_vec(),
+            input: data.input.clone(),
+            record: JobRecord {
+                status: String::from("queued"),
+                output: None,
+                created_at: now(),
+                started_at: None,
+                ended_at: None,
+                expires_after: humantime::format_duration(self.expires_after).to_string(),
+            },
+            ended: None,
+        };
+        state.jobs.insert(id, job);
+        state.queued.push_back(id);
+        Ok(id)
+    }
+
+    async fn find_by_tag(&self, tag: &str) -> Result<Option<u32>, QueueError> {
+        let mut state = self.state.lock().unwrap();
+        state.expire(self.expires_after);
+        Ok(state
+            .jobs
+            .iter()
+            .filter(|(_, job)| job.tags.iter().any(|t| t == tag))
+            .map(|(id, _)| *id)
+            .max())
+    }
+
+    async fn get(&self, id: u32) -> Result<Option<JobRecord>, QueueError> {
+        let mut state = self.state.lock().unwrap();
+        state.expire(self.expires_after);
+        Ok(state.jobs.get(&id).map(|job| job.record.clone()))
+    }
+
+    async fn get_input(&self, id: u32) -> Result<Option<InputRecord>, QueueError> {
+        let mut state = self.state.lock().unwrap();
+        state.expire(self.expires_after);
+        Ok(state.jobs.get(&id).map(|job| InputRecord {
+            input: job.input.clone(),
+            created_at: job.record.created_at.clone(),
+        }))
+    }
+
+    async fn status(&self, id: u32) -> Result<Option<String>, QueueError> {
+        let mut state = self.state.lock().unwrap();
+        state.expire(self.expires_after);
+        Ok(state.jobs.get(&id).map(|job| job.record.status.clone()))
+    }
+
+    async fn summary(&self, id: u32) -> Result<Option<JobSummary>, QueueError> {
+        let mut state = self.state.lock().unwrap();
+        state.expire(self.expires_after);
+        Ok(state.jobs.get(&id).map(|job| JobSummary {
+            status: job.record.status.clone(),
+            created_at: job.record.created_at.clone(),
+            started_at: job.record.started_at.clone(),
+            ended_at: job.record.ended_at.clone(),
+            expires_after: job.record.expires_after.clone(),
+        }))
+    }
+
+    async fn next_job(&self) -> Result<Option<JobInput>, QueueError> {
+        let mut state = self.state.lock().unwrap();
+        while let Some(id) = state.queued.pop_front() {
+            if let Some(job) = state.jobs.get_mut(&id) {
+                job.record.status = String::from("running");
+                job.record.started_at = Some(now());
+                return Ok(Some(JobInput {
+                    id,
+                    input: job.input.clone(),
+                }));
+            }
+        }
+        Ok(None)
+    }
+
+    async fn complete(&self, id: u32, output: Output) -> Result<(), QueueError> {
+        self.finish(id, "completed", JobResult::Completed(output))
+    }
+
+    async fn fail(&self, id: u32, failure: Failure) -> Result<(), QueueError> {
+        self.finish(id, "failed", JobResult::Failed { failure })
+    }
+
+    async fn size(&self) -> Result<usize, QueueError> {
+        Ok(self.state.lock().unwrap().queued.len())
+    }
+
+    async fn save_batch(&self, batch_id: &str, tags: &[String]) -> Result<(), QueueError> {
+        let mut state = self.state.lock().unwrap();
+        state.expire(self.expires_after);
+        state
+            .batches
+            .insert(batch_id.to_string(), (tags.to_vec(), SystemTime::now()));
+        Ok(())
+    }
+
+    async fn find_batch(&self, batch_id: &str) -> Result<Option<Vec<String>>, QueueError> {
+        let mut state = self.state.lock().unwrap();
+        state.expire(self.expires_after);
+        Ok(state.batches.get(batch_id).map(|(tags, _)| tags.clone()))
+    }
+}
@@ -0,0 +1,253 @@
+//! Reader of the parKVFinder results file (`KVFinderWeb.KVFinder.results.toml`).
+//! Results are kept by section (`[RESULTS.VOLUME]`, `[RESULTS.AREA]`, ...) with
+//! one key per cavity; they are regrouped by cavity.
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Results of a parKVFinder run
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Report {
+    /// Cavities sorted by name (KAA, KAB, ...)
+    pub cavities: Vec<Cavity>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Cavity {
+    /// Cavity name, as the residue name of its points in the cavities PDB
+    pub name: String,
+    /// Volume (cubic angstroms)
+    pub volume: f64,
+    /// Area (square angstroms)
+    pub area: f64,
+    /// Maximum depth (angstroms), if computed
+    pub max_depth: Option<f64>,
+    /// Average depth (angstroms), if computed
+    pub avg_depth: Option<f64>,
+    /// Average hydropathy of the surface residues, if computed
+    pub avg_hydropathy: Option<f64>,
+    /// Residues lining the cavity
+    pub residues: Vec<Residue>,
+}
+
+/// Selection of cavities, e.g. `?min_volume=50&top=3&sort=volume`: cavities
+/// with at least `min_volume` and `min_area`, then the `top` ones by `sort`
+/// (largest first; by volume if not set). Without `sort` and `top`, cavities
+/// keep their order (by name).
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct CavityFilter {
+    pub min_volume: Option<f64>,
+    pub min_area: Option<f64>,
+    pub top: Option<usize>,
+    pub sort: Option<SortKey>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SortKey {
+    Volume,
+    Area,
+    /// Maximum depth (cavities without depth are the last ones)
+    Depth,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Residue {
+    /// Residue sequence number
+    pub number: String,
+    pub chain: String,
+    pub name: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+struct ResultsFile {
+    results: Results,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+struct Results {
+    volume: BTreeMap<String, f64>,
+    area: BTreeMap<String, f64>,
+    #[serde(default)]
+    max_depth: BTreeMap<String, f64>,
+    #[serde(default)]
+    avg_depth: BTreeMap<String, f64>,
+    /// Besides cavities, it has the range of the hydropathy scale
+    /// (e.g. `EisenbergWeiss = [-1.42, 2.6]`)
+    #[serde(default)]
+    avg_hydropathy: BTreeMap<String, toml::Value>,
+    #[serde(default)]
+    residues: BTreeMap<String, Vec<(String, String, String)>>,
+}
+
+impl CavityFilter {
+    /// No selection (all cavities, by name)
+    pub fn is_empty(&self) -> bool {
+        self.min_volume.is_none() && self.min_area.is_none() && self.top.is_none() && self.sort.is_none()
+    }
+
+    pub fn apply(&self, report: Report) -> Report {
+        let mut cavities: Vec<Cavity> = report
+            .cavities
+            .into_iter()
+            .filter(|c| self.min_volume.map_or(true, |min| c.volume >= min))
+            .filter(|c| self.min_area.map_or(true, |min| c.area >= min))
+            .collect();
+        if self.sort.is_some() || self.top.is_some() {
+            let key = |c: &Cavity| match self.sort.unwrap_or(SortKey::Volume) {
+                SortKey::Volume => c.volume,
+                SortKey::Area => c.area,
+                SortKey::Depth => c.max_depth.unwrap_or(f64::NEG_INFINITY),
+            };
+            // stable: cavities with the same value keep their order
+            cavities.sort_by(|a, b| key(b).total_cmp(&key(a)));
+        }
+        if let Some(top) = self.top {
+            cavities.truncate(top);
+        }
+        Report { cavities }
+    }
+}
+
+/// Keep only the points (ATOM and HETATM records) of the cavities in `report`
+/// in a cavities PDB, where the residue name of a point is its cavity name.
+/// Other records are kept.
+pub fn filter_pdb(pdb: &str, report: &Report) -> String {
+    let mut filtered = String::with_capacity(pdb.len());
+    for line in pdb.lines() {
+        let is_point = line.starts_with("ATOM") || line.starts_with("HETATM");
+        let cavity = line.get(17..20).unwrap_or("").trim();
+        if !is_point || report.cavities.iter().any(|c| c.name == cavity) {
+            filtered.push_str(line);
+            filtered.push('\n');
+        }
+    }
+    filtered
+}
+
+/// Parse a results file. Cavities are the ones with a volume; other sections
+/// are optional (depth and hydropathy are computed only on request).
+pub fn parse(text: &str) -> Result<Report, toml::de::Error> {
+    let Results {
+        volume,
+        area,
+        max_depth,
+        avg_depth,
+        avg_hydropathy,
+        mut residues,
+    } = toml::from_str::<ResultsFile>(text)?.results;
+    let cavities = volume
+        .into_iter()
+        .map(|(name, volume)| Cavity {
+            area: area.get(&name).copied().unwrap_or(0.0),
+            max_depth: max_depth.get(&name).copied(),
+            avg_depth: avg_depth.get(&name).copied(),
+            avg_hydropathy: avg_hydropathy.get(&name).and_then(|v| match v {
+                toml::Value::Float(f) => Some(*f),
+                toml::Value::Integer(i) => Some(*i as f64),
+                _ => None,
+            }),
+            residues: residues
+                .remove(&name)
+                .unwrap_or_default()
+                .into_iter()
+                .map(|(number, chain, name)| Residue { number, chain, name })
+                .collect(),
+            name,
+            volume,
+        })
+        .collect();
+    Ok(Report { cavities })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Results file as written by parKVFinder v1.2.0 for examples/1FMO.pdb, with
+    /// depth and hydropathy
+    const RESULTS: &str = include_str!("../../../examples/KVFinderWeb.KVFinder.results.toml");
+
+    fn residue(number: &str, name: &str) -> Residue {
+        Residue {
+            number: number.to_string(),
+            chain: String::from("E"),
+            name: name.to_string(),
+        }
+    }
+
+    #[test]
+    fn results_are_read_by_cavity() {
+        let report = parse(RESULTS).unwrap();
+        let names: Vec<&str> = report.cavities.iter().map(|c| c.name.as_str()).collect();
+        // EisenbergWeiss (range of the hydropathy scale) is not a cavity
+        assert_eq!(names, ["KAA", "KAB", "KAC"]);
+        let values: Vec<_> = report
+            .cavities
+            .iter()
+            .map(|c| (c.volume, c.area, c.max_depth, c.avg_depth, c.avg_hydropathy, c.residues.len()))
+            .collect();
+        assert_eq!(
+            values,
+            [
+                (1672.27, 1040.68, Some(9.32), Some(3.84), Some(-0.19), 20),
+                (137.16, 126.41, Some(3.59), Some(1.23), Some(-0.72), 6),
+                (47.52, 66.4, Some(0.0), Some(0.0), Some(0.41), 4),
+            ]
+        );
+        assert_eq!(report.cavities[0].residues[0], residue("49", "LEU"));
+        assert_eq!(
+            report.cavities[2].residues,
+            [residue("56", "ARG"), residue("232", "ALA"), residue("235", "TYR"), residue("236", "PRO")]
+        );
+    }
+
+    #[test]
+    fn depth_and_hydropathy_are_optional() {
+        let start = RESULTS.find("[RESULTS.MAX_DEPTH]").unwrap();
+        let end = RESULTS.find("[RESULTS.RESIDUES]").unwrap();
+        let results = format!("{}{}", &RESULTS[..start], &RESULTS[end..]);
+        let report = parse(&results).unwrap();
+        assert_eq!(report.cavities.len(), 3);
+        for (cavity, expected) in report.cavities.iter().zip(parse(RESULTS).unwrap().cavities) {
+            assert_eq!((cavity.max_depth, cavity.avg_depth, cavity.avg_hydropathy), (None, None, None));
+            assert_eq!((cavity.volume, cavity.area), (expected.volume, expected.area));
+            assert_eq!(cavity.residues, expected.residues);
+        }
+    }
+
+    #[test]
+    fn cavities_are_selected_by_filter() {
+        let names = |filter: CavityFilter| -> Vec<String> {
+            filter.apply(parse(RESULTS).unwrap()).cavities.into_iter().map(|c| c.name).collect()
+        };
+        assert_eq!(names(CavityFilter::default()), ["KAA", "KAB", "KAC"]);
+        let filter = CavityFilter {
+            min_volume: Some(137.16),
+            ..CavityFilter::default()
+        };
+        assert_eq!(names(filter), ["KAA", "KAB"]);
+        let filter = CavityFilter {
+            min_area: Some(100.0),
+            top: Some(1),
+            sort: Some(SortKey::Area),
+            ..CavityFilter::default()
+        };
+        assert_eq!(names(filter), ["KAA"]);
+        let filter = CavityFilter {
+            min_volume: Some(40.0),
+            min_area: Some(100.0),
+            ..CavityFilter::default()
+        };
+        assert_eq!(names(filter), ["KAA", "KAB"]);
+    }
+
+    #[test]
+    fn results_without_cavities_are_empty() {
+        let results = "title = \"parKVFinder results file\"\n\n[RESULTS]\n\n[RESULTS.VOLUME]\n\n[RESULTS.AREA]\n";
+        assert_eq!(parse(results).unwrap(), Report { cavities: Vec::new() });
+        assert!(parse("[RESULTS.AREA]\nKAA = 1.0\n").is_err());
+    }
+}
@@ -1,21 +1,50 @@
-use super::{Data, Input, Output};
-use actix_web::{web, HttpResponse, Responder};
-use fasthash::city;
-use reqwest;
+use super::config::{ServerConfig, ValidationPolicy};
+use super::error::{ErrorCode, KvError, Violation};
+use super::pdb::Stats;
+use super::queue::{JobQueue, JobResult};
+use super::report::{self, CavityFilter, Report, SortKey};
+use super::{Data, Failure, Input, Output};
+use actix_multipart::{Field, Multipart};
+use actix_web::dev::Decompress;
+use actix_web::{error, guard, http::header, web, HttpRequest, HttpResponse, Responder};
+use flate2::read::MultiGzDecoder;
+use futures_util::TryStreamExt;
+use schemars::{schema_for, JsonSchema};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use serde_json;
 use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::io::Read;
 
 #[derive(Serialize, Deserialize)]
 struct Job {
     #[serde(default)]
     id: String, // this id is the same as tag_id (NOT queue_id)
     status: String,
-    output: Option<Output>,
+    output: Option<JobOutput>,
+    /// Why the job failed (sent only for failed jobs)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    failure: Option<Failure>,
     created_at: String,
     started_at: Option<String>,
     ended_at: Option<String>,
     expires_after: String,
+    /// Statistics of the submitted structure (sent only in create responses)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    stats: Option<Stats>,
+    /// Encoding of `output` (sent only if not text)
+    #[serde(default, skip_serializing_if = "Encoding::is_text")]
+    encoding: Encoding,
+}
+
+#[derive(Serialize, Deserialize)]
+struct JobOutput {
+    #[serde(flatten)]
+    output: Output,
+    /// `report` parsed (null if it cannot be read)
+    results: Option<Report>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -24,13 +53,161 @@ struct JobInput {
     id: String,
     input: Input,
     created_at: String,
+    /// Encoding of `input.pdb` and `input.pdb_ligand` (sent only if not text)
+    #[serde(default, skip_serializing_if = "Encoding::is_text")]
+    encoding: Encoding,
 }
 
-#[derive(Serialize, Deserialize)]
-struct QueueConfig<'a> {
-    timeout: &'a str,
-    expires_after: &'a str,
-    retries: i32,
+/// Encoding of structures and results in json bodies: text (default) or
+/// "zstd", the base64 of zstd compressed text, as kept in queue (see
+/// `compress`). With "zstd" results are sent as they are kept in queue.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    #[default]
+    Text,
+    Zstd,
+}
+
+impl Encoding {
+    fn is_text(&self) -> bool {
+        *self == Encoding::Text
+    }
+}
+
+/// Query string of GET /retrieve-input/:id, e.g. `?encoding=zstd`
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EncodingQuery {
+    #[serde(default)]
+    encoding: Encoding,
+}
+
+/// Query string of GET /:id: encoding of the output and selection of
+/// cavities (see `CavityFilter`), e.g. `?top=3&sort=volume`
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct JobQuery {
+    #[serde(default)]
+    encoding: Encoding,
+    min_volume: Option<f64>,
+    min_area: Option<f64>,
+    top: Option<usize>,
+    sort: Option<SortKey>,
+}
+
+impl JobQuery {
+    fn filter(&self) -> CavityFilter {
+        CavityFilter {
+            min_volume: self.min_volume,
+            min_area: self.min_area,
+            top: self.top,
+            sort: self.sort,
+        }
+    }
+}
+
+/// Bulk submission (POST /batch): a list of inputs, or a list of structures
+/// (`pdbs`) that share the same `settings` (or `parameters`).
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BatchInput {
+    #[serde(default)]
+    inputs: Vec<serde_json::Value>,
+    #[serde(default)]
+    pdbs: Vec<String>,
+    settings: Option<serde_json::Value>,
+    parameters: Option<String>,
+    /// Encoding of `pdbs`
+    #[serde(default)]
+    encoding: Encoding,
+}
+
+/// Maximum size (bytes) of an uploaded file (see `ServerConfig::upload_limit`)
+#[derive(Clone, Copy)]
+pub struct UploadLimit(pub usize);
+
+/// Maximum size (bytes) of a structure sent compressed ("zstd" encoding) once
+/// decompressed: the json payload limit (see `ServerConfig::json_limit`)
+#[derive(Clone, Copy)]
+pub struct DecompressLimit(pub usize);
+
+/// Result of submitting an input
+enum Submission {
+    /// a job with the same (canonical) input is already in queue
+    Existing(Box<Job>),
+    /// a job was created, there were `queue_size` jobs waiting before it
+    Created { id: String, queue_size: usize },
+}
+
+/// Routes of the web service. They are served at the root (used by the PyMOL
+/// plugin) and under the `/v1` prefix (e.g. `POST /v1/create`), the version that
+/// clients should use to keep working when the API changes.
+/// POST /create also accepts file uploads (multipart/form-data) and POST /batch
+/// reads json payloads up to `batch_json_limit` bytes.
+pub fn routes(config: &ServerConfig) -> impl Fn(&mut web::ServiceConfig) + Clone {
+    let batch_json_limit = config.batch_json_limit;
+    let upload_limit = UploadLimit(config.upload_limit);
+    let decompress_limit = DecompressLimit(config.json_limit);
+    move |cfg| {
+        cfg.app_data(web::Data::new(decompress_limit))
+            .route("/", web::get().to(hello))
+            .route("/limits", web::get().to(limits))
+            .route("/schema", web::get().to(schema))
+            .route("/{id}", web::get().to(ask))
+            .route("/retrieve-input/{id}", web::get().to(retrieve_input))
+            .service(
+                web::resource("/create")
+                    .app_data(web::Data::new(upload_limit))
+                    .route(web::post().guard(guard::fn_guard(is_multipart)).to(create_upload))
+                    .route(web::post().to(create)),
+            )
+            .service(
+                web::resource("/batch")
+                    .app_data(
+                        web::JsonConfig::default()
+                            .limit(batch_json_limit)
+                            .error_handler(json_error_handler),
+                    )
+                    .route(web::post().to(batch)),
+            )
+            .route("/batch/{batch_id}", web::get().to(ask_batch))
+            // after other routes with two segments (e.g. /batch/:batch_id)
+            .route("/{id}/status", web::get().to(ask_status))
+            .route("/{id}/cavity.pdb", web::get().to(cavity_pdb))
+            .route("/{id}/report.toml", web::get().to(report_toml))
+            .route("/{id}/report.json", web::get().to(report_json))
+            .route("/{id}/log", web::get().to(log));
+    }
+}
+
+/// Error handler of `web::QueryConfig`: invalid query strings are answered
+/// with an INVALID_QUERY error.
+pub fn query_error_handler(err: error::QueryPayloadError, _req: &HttpRequest) -> error::Error {
+    KvError::InvalidQuery(err.to_string()).into()
+}
+
+/// Error handler of `web::JsonConfig`: payloads that are not json (or too
+/// large) are answered with an INVALID_JSON error.
+pub fn json_error_handler(err: error::JsonPayloadError, _req: &HttpRequest) -> error::Error {
+    KvError::InvalidJson {
+        field: None,
+        message: err.to_string(),
+    }
+    .into()
+}
+
+/// Read a json value as `T`. Errors carry the path of the value that could not
+/// be read, e.g. "settings.probes.probe_in".
+fn from_json<T: DeserializeOwned>(value: serde_json::Value) -> Result<T, KvError> {
+    serde_path_to_error::deserialize(value).map_err(|e| {
+        let path = e.path().to_string();
+        KvError::InvalidJson {
+            // "." is the path of the whole value
+            field: if path == "." { None } else { Some(path) },
+            message: e.into_inner().to_string(),
+        }
+    })
 }
 
 // GET /
@@ -38,61 +215,130 @@ pub async fn hello() -> impl Responder {
     "KVFinder-web service"
 }
 
-pub fn create_ocypod_queue(queue_name: &str, timeout: &str, expires_after: &str, retries: i32) {
-    let client = reqwest::Client::new();
-    let queue_url = format!("http://ocypod:8023/queue/{}", queue_name);
-    let queue_config = QueueConfig {
-        timeout,
-        expires_after,
-        retries,
-    };
-    let _response = client.put(&queue_url).json(&queue_config).send();
-    // match _response {
-    //     Ok(_) => HttpResponse::Ok().json(json!({"id":data.tags[0]})),
-    //     Err(e) => HttpResponse::InternalServerError().body(format!("{:?}", e)),
-    // }
+/// Job input of POST /create
+// `Input` and the fields removed before it is deserialized (see `decoded`
+// and `with_parameters`), only used for the JSON Schema of GET /schema
+#[derive(JsonSchema)]
+#[serde(rename = "Input", deny_unknown_fields)]
+#[allow(dead_code)]
+struct ClientInput {
+    #[serde(flatten)]
+    input: Input,
+    /// Content of a parKVFinder parameters file (parameters.toml), sent
+    /// instead of `settings`; its [SETTINGS] table is read as `settings`
+    parameters: Option<String>,
+    /// Encoding of `pdb` and `pdb_ligand`, also used for the output of an
+    /// existing job (default: text)
+    encoding: Option<Encoding>,
 }
 
-/// Get job queue id for a job "tag id". The tag id is created applying a hash
-/// function to received data (input data). It is the id sent to users. The queue
-/// id is for internal use only and increase sequentially.
-/// If tag id is not found returns Ok(None).
-fn get_queue_id(tag_id: &String) -> Result<Option<u32>, reqwest::Error> {
-    let url = format!("http://ocypod:8023/tag/{}", tag_id);
+// GET /schema
+// Responds with the JSON Schema of job inputs (POST /create).
+pub async fn schema() -> impl Responder {
+    HttpResponse::Ok().json(schema_for!(ClientInput))
+}
 
-    // ids because in theory could be more than one with the same tag, BUT if this happen there is an error
-    // if tag_id (hash64) not found in queue Ok(None)
-    // if request fail return Err (possible problem in queue server)
-    let mut ids: Vec<u32> = reqwest::get(url.as_str())?.json()?;
-    // pop returns last id (should have only one or zero) or None
-    Ok(ids.pop())
+// GET /limits
+// Responds with the validation policy (limits) applied to job inputs.
+pub async fn limits(policy: web::Data<ValidationPolicy>) -> impl Responder {
+    HttpResponse::Ok().json(policy.get_ref())
 }
 
 /// Use tag id job to get job data from queue.
+/// The tag id is created applying a hash function to received data (input data).
+/// It is the id sent to users. The queue id is for internal use only and
+/// increase sequentially.
 /// If tag id not found returns Ok(None).
-fn get_job(tag_id: String) -> Result<Option<Job>, reqwest::Error> {
-    let queue_id = get_queue_id(&tag_id);
-    let job = |queue_id| {
-        let url = format!("http://ocypod:8023/job/{}?fields=status,output,created_at,started_at,ended_at,expires_after", queue_id);
-        let mut j: Job = reqwest::get(url.as_str())?.json()?;
-        j.id = tag_id;
-        if let Some(output) = &mut j.output {
-            output.pdb_kv = super::decompress(&output.pdb_kv).expect("decompression error");
-            output.report = super::decompress(&output.report).expect("decompression error");
-            output.log = super::decompress(&output.log).expect("decompression error");
+/// With `Encoding::Zstd` the output is sent as kept in queue (not decompressed).
+/// Results and cavities (`pdb_kv`) of the output are reduced to the cavities
+/// selected by `filter`.
+async fn get_job(
+    queue: &dyn JobQueue,
+    tag_id: String,
+    encoding: Encoding,
+    filter: &CavityFilter,
+) -> Result<Option<Job>, KvError> {
+    let record = match queue.find_by_tag(&tag_id).await? {
+        Some(queue_id) => queue.get(queue_id).await?,
+        // if queue_id is None (tag_id not found)
+        None => None,
+    };
+    let r = match record {
+        Some(r) => r,
+        None => return Ok(None),
+    };
+    let mut failure = None;
+    let output = match r.output {
+        Some(JobResult::Failed { failure: f }) => {
+            failure = Some(match encoding {
+                Encoding::Zstd => f,
+                Encoding::Text => Failure {
+                    stdout: decompress(&f.stdout)?,
+                    stderr: decompress(&f.stderr)?,
+                    log: f.log.as_ref().map(decompress).transpose()?,
+                    ..f
+                },
+            });
+            None
         }
-        // j.output.pdb_kv = super::decompress(&j.output.pdb_kv).expect("decompression error");
-
-        Ok(Some(j))
+        Some(JobResult::Completed(output)) => {
+            let report = decompress(&output.report)?;
+            let mut results = report::parse(&report).ok();
+            // cavities PDB with the selected cavities only
+            let mut pdb_kv = None;
+            if !filter.is_empty() {
+                let selected = filter.apply(read_results(&report)?);
+                pdb_kv = Some(report::filter_pdb(&decompress(&output.pdb_kv)?, &selected));
+                results = Some(selected);
+            }
+            let output = match encoding {
+                Encoding::Zstd => Output {
+                    pdb_kv: match pdb_kv {
+                        Some(pdb_kv) => compress(&pdb_kv)?,
+                        None => output.pdb_kv,
+                    },
+                    ..output
+                },
+                Encoding::Text => Output {
+                    pdb_kv: match pdb_kv {
+                        Some(pdb_kv) => pdb_kv,
+                        None => decompress(&output.pdb_kv)?,
+                    },
+                    report,
+                    log: decompress(&output.log)?,
+                },
+            };
+            Some(JobOutput { output, results })
+        }
+        None => None,
     };
+    Ok(Some(Job {
+        id: tag_id,
+        status: r.status,
+        output,
+        failure,
+        created_at: r.created_at,
+        started_at: r.started_at,
+        ended_at: r.ended_at,
+        expires_after: r.expires_after,
+        stats: None,
+        encoding,
+    }))
+}
 
-    match queue_id {
-        Err(e) => Err(e),
-        // if queue_id is None (tag_id not found)
-        Ok(None) => return Ok(None),
-        // return job data in json
-        Ok(Some(queue_id)) => return job(queue_id),
-    }
+/// Read a results file received from queue (see `report::parse`).
+fn read_results(report: &str) -> Result<Report, KvError> {
+    report::parse(report).map_err(|e| KvError::Corrupted(format!("cannot read results file: {}", e)))
+}
+
+/// Decompress data received from queue.
+fn decompress(b64: &String) -> Result<String, KvError> {
+    super::decompress(b64).map_err(|e| KvError::Corrupted(e.to_string()))
+}
+
+/// Compress data to be sent to queue.
+fn compress(s: &String) -> Result<String, KvError> {
+    super::compress(s).map_err(|e| KvError::Internal(e.to_string()))
 }
 
 /// GET /:id
@@ -100,114 +346,1011 @@ fn get_job(tag_id: String) -> Result<Option<Job>, reqwest::Error> {
 /// If the :id is found returns an HTTP response with output data which includes
 /// processing status: "queued", "running", "completed"...
 /// If :id is not found returns NOT FOUND (Code 404)
-pub async fn ask(id: web::Path<String>) -> impl Responder {
+/// With `?encoding=zstd` the output is sent compressed (see `Encoding`) and with
+/// `?min_volume=`, `?min_area=`, `?top=` and `?sort=` only the selected cavities
+/// are sent (see `CavityFilter`).
+pub async fn ask(
+    id: web::Path<String>,
+    query: web::Query<JobQuery>,
+    queue: web::Data<dyn JobQueue>,
+) -> Result<HttpResponse, KvError> {
     let tag_id = id.into_inner();
-    let job = get_job(tag_id);
-    match job {
-        Err(e) => HttpResponse::InternalServerError().body(format!("{:?}", e)),
-        Ok(None) => HttpResponse::NotFound().finish(),
-        Ok(Some(j)) => HttpResponse::Ok().json(j),
+    match get_job(queue.get_ref(), tag_id.clone(), query.encoding, &query.filter()).await? {
+        None => Err(KvError::JobNotFound(tag_id)),
+        Some(j) => Ok(HttpResponse::Ok().json(j)),
     }
 }
 
+/// GET /:id/status
+/// Job data without output (status and times), for clients polling a job.
+pub async fn ask_status(id: web::Path<String>, queue: web::Data<dyn JobQueue>) -> Result<HttpResponse, KvError> {
+    let tag_id = id.into_inner();
+    let queue = queue.get_ref();
+    let summary = match queue.find_by_tag(&tag_id).await? {
+        Some(queue_id) => queue.summary(queue_id).await?,
+        None => None,
+    };
+    match summary {
+        None => Err(KvError::JobNotFound(tag_id)),
+        Some(s) => Ok(HttpResponse::Ok().json(json!({
+            "id": tag_id,
+            "status": s.status,
+            "created_at": s.created_at,
+            "started_at": s.started_at,
+            "ended_at": s.ended_at,
+            "expires_after": s.expires_after,
+        }))),
+    }
+}
+
+/// Output of a completed job, as kept in queue (compressed).
+/// Errors if the job is not found or not completed.
+async fn get_output(queue: &dyn JobQueue, tag_id: String) -> Result<Output, KvError> {
+    let record = match queue.find_by_tag(&tag_id).await? {
+        Some(queue_id) => queue.get(queue_id).await?,
+        None => None,
+    };
+    match record {
+        None => Err(KvError::JobNotFound(tag_id)),
+        Some(r) => match r.output {
+            Some(JobResult::Completed(output)) if r.status == "completed" => Ok(output),
+            _ => Err(KvError::JobNotCompleted(tag_id, r.status)),
+        },
+    }
+}
+
+/// GET /:id/cavity.pdb
+/// Cavities found by parKVFinder (PDB), all or the ones selected by the query
+/// string (see `CavityFilter`).
+pub async fn cavity_pdb(
+    id: web::Path<String>,
+    filter: web::Query<CavityFilter>,
+    queue: web::Data<dyn JobQueue>,
+) -> Result<HttpResponse, KvError> {
+    let output = get_output(queue.get_ref(), id.into_inner()).await?;
+    let mut pdb_kv = decompress(&output.pdb_kv)?;
+    if !filter.is_empty() {
+        let selected = filter.apply(read_results(&decompress(&output.report)?)?);
+        pdb_kv = report::filter_pdb(&pdb_kv, &selected);
+    }
+    Ok(HttpResponse::Ok().content_type("chemical/x-pdb").body(pdb_kv))
+}
+
+/// GET /:id/report.toml
+/// parKVFinder results file (TOML).
+pub async fn report_toml(id: web::Path<String>, queue: web::Data<dyn JobQueue>) -> Result<HttpResponse, KvError> {
+    let output = get_output(queue.get_ref(), id.into_inner()).await?;
+    Ok(HttpResponse::Ok()
+        .content_type("application/toml")
+        .body(decompress(&output.report)?))
+}
+
+/// GET /:id/report.json
+/// parKVFinder results by cavity (see `report::Report`), all or the ones
+/// selected by the query string (see `CavityFilter`).
+pub async fn report_json(
+    id: web::Path<String>,
+    filter: web::Query<CavityFilter>,
+    queue: web::Data<dyn JobQueue>,
+) -> Result<HttpResponse, KvError> {
+    let output = get_output(queue.get_ref(), id.into_inner()).await?;
+    let report = read_results(&decompress(&output.report)?)?;
+    Ok(HttpResponse::Ok().json(filter.apply(report)))
+}
+
+/// GET /:id/log
+/// parKVFinder log.
+pub async fn log(id: web::Path<String>, queue: web::Data<dyn JobQueue>) -> Result<HttpResponse, KvError> {
+    let output = get_output(queue.get_ref(), id.into_inner()).await?;
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; charset=utf-8")
+        .body(decompress(&output.log)?))
+}
+
 /// POST /create
 /// Receives input data (json sent by users), creates a job, sends it to queue and
-/// responds the user (http response) with the job id.
+/// responds the user (http response) with the job id and statistics (atoms,
+/// residues and chains) of the structure.
 /// Also, before create a job, it checks if a job with the same parameters (hash -> tag id)
 /// are not yet into queue. If it is, it responds with job data.
-pub async fn create(job_input: web::Json<Input>) -> impl Responder {
-    // json input values to input struct
-    let input = job_input.into_inner();
-    // check input values (pdb, pdb_ligand, ...)
-    if let Err(e) = &input.check() {
-        return HttpResponse::BadRequest().body(format!("{:?}", e));
+pub async fn create(
+    job_input: web::Json<serde_json::Value>,
+    queue: web::Data<dyn JobQueue>,
+    policy: web::Data<ValidationPolicy>,
+    decompress_limit: web::Data<DecompressLimit>,
+) -> Result<HttpResponse, KvError> {
+    let (submission, stats) = submit(queue.get_ref(), &policy, **decompress_limit, job_input.into_inner()).await?;
+    Ok(submitted(submission, stats))
+}
+
+/// Response of POST /create
+fn submitted(submission: Submission, stats: Stats) -> HttpResponse {
+    match submission {
+        Submission::Existing(j) => HttpResponse::Ok().json(Job {
+            stats: Some(stats),
+            ..*j
+        }),
+        // job is created, return job id and queue size (number of jobs in queue)
+        Submission::Created { id, queue_size } => {
+            HttpResponse::Ok().json(json!({"id": id, "queue_size": queue_size, "stats": stats}))
+        }
+    }
+}
+
+fn is_multipart(ctx: &guard::GuardContext) -> bool {
+    ctx.header::<header::ContentType>()
+        .is_some_and(|ct| ct.0.essence_str() == "multipart/form-data")
+}
+
+/// POST /create (multipart/form-data)
+/// Same as POST /create with the input sent as files: `protein` (pdb),
+/// optional `ligand` (pdb_ligand) and optional `settings`, written in TOML or
+/// json, or `parameters`, a parKVFinder parameters file. Structures may be
+/// gzip compressed.
+pub async fn create_upload(
+    req: HttpRequest,
+    payload: web::Payload,
+    queue: web::Data<dyn JobQueue>,
+    policy: web::Data<ValidationPolicy>,
+    limit: web::Data<UploadLimit>,
+    decompress_limit: web::Data<DecompressLimit>,
+) -> Result<HttpResponse, KvError> {
+    // request bodies with Content-Encoding (e.g. gzip) are decompressed
+    let mut payload = Multipart::new(req.headers(), Decompress::from_headers(payload, req.headers()));
+    let mut input = serde_json::Map::new();
+    while let Some(mut field) = payload.try_next().await.map_err(|e| upload_error(None, e))? {
+        let name = field.name().unwrap_or_default().to_string();
+        let key = match name.as_str() {
+            "protein" => "pdb",
+            "ligand" => "pdb_ligand",
+            "settings" => "settings",
+            "parameters" => "parameters",
+            _ => {
+                return Err(upload_error(
+                    Some(name),
+                    "unknown part, expected `protein`, `ligand`, `settings` or `parameters`",
+                ))
+            }
+        };
+        if input.contains_key(key) {
+            return Err(upload_error(Some(name), "duplicated part"));
+        }
+        let text = read_part(&mut field, &name, limit.0).await?;
+        let value = if key == "settings" {
+            read_settings(&text).map_err(|e| upload_error(Some(name), e))?
+        } else {
+            serde_json::Value::String(text)
+        };
+        input.insert(key.to_string(), value);
+    }
+    if !input.contains_key("pdb") {
+        return Err(upload_error(Some("protein".to_string()), "missing part"));
+    }
+    let (submission, stats) = submit(queue.get_ref(), &policy, **decompress_limit, serde_json::Value::Object(input)).await?;
+    Ok(submitted(submission, stats))
+}
+
+fn upload_error(field: Option<String>, message: impl ToString) -> KvError {
+    KvError::InvalidUpload {
+        field,
+        message: message.to_string(),
+    }
+}
+
+/// Read an uploaded file (up to `limit` bytes) as text, decompressing gzip
+/// files (detected by their magic number).
+async fn read_part(field: &mut Field, name: &str, limit: usize) -> Result<String, KvError> {
+    let too_large = || upload_error(Some(name.to_string()), format!("file larger than {} bytes", limit));
+    let mut data = Vec::new();
+    while let Some(chunk) = field.try_next().await.map_err(|e| upload_error(Some(name.to_string()), e))? {
+        if data.len() + chunk.len() > limit {
+            return Err(too_large());
+        }
+        data.extend_from_slice(&chunk);
+    }
+    if data.starts_with(&[0x1f, 0x8b]) {
+        let mut text = Vec::new();
+        MultiGzDecoder::new(data.as_slice())
+            .take(limit as u64 + 1)
+            .read_to_end(&mut text)
+            .map_err(|e| upload_error(Some(name.to_string()), format!("cannot decompress gzip file: {}", e)))?;
+        if text.len() > limit {
+            return Err(too_large());
+        }
+        data = text;
     }
+    String::from_utf8(data).map_err(|_| upload_error(Some(name.to_string()), "file is not UTF-8 text"))
+}
+
+/// Settings file as json value: a json object or a TOML document with the
+/// same tables (`[modes]`, `[probes]`, ...).
+fn read_settings(text: &str) -> Result<serde_json::Value, String> {
+    if text.trim_start().starts_with('{') {
+        serde_json::from_str(text).map_err(|e| format!("invalid json settings: {}", e))
+    } else {
+        toml::from_str(text).map_err(|e| format!("invalid TOML settings: {}", e))
+    }
+}
+
+/// Remove `encoding` from a json input and decompress `pdb` and `pdb_ligand`
+/// if they are sent compressed (see `Encoding`), up to `limit` bytes each. The
+/// encoding is also used for the output of an existing job.
+fn decoded(mut value: serde_json::Value, limit: DecompressLimit) -> Result<(serde_json::Value, Encoding), KvError> {
+    let input = match value.as_object_mut() {
+        Some(input) => input,
+        None => return Ok((value, Encoding::Text)),
+    };
+    let encoding = match input.remove("encoding") {
+        Some(encoding) => serde_json::from_value::<Encoding>(encoding).map_err(|e| KvError::InvalidJson {
+            field: Some(String::from("encoding")),
+            message: e.to_string(),
+        })?,
+        None => Encoding::Text,
+    };
+    if encoding == Encoding::Zstd {
+        for field in ["pdb", "pdb_ligand"] {
+            if let Some(serde_json::Value::String(data)) = input.get_mut(field) {
+                *data = super::decompress_limited(data, limit.0).map_err(|e| KvError::InvalidJson {
+                    field: Some(field.to_string()),
+                    message: format!("cannot decompress zstd data: {}", e),
+                })?;
+            }
+        }
+    }
+    Ok((value, encoding))
+}
+
+/// Replace `parameters`, a parKVFinder parameters file (TOML) sent instead of
+/// `settings`, by the settings it holds.
+fn with_parameters(mut value: serde_json::Value) -> Result<serde_json::Value, KvError> {
+    let input = match value.as_object_mut() {
+        Some(input) => input,
+        None => return Ok(value),
+    };
+    let parameters = match input.remove("parameters") {
+        Some(parameters) => parameters,
+        None => return Ok(value),
+    };
+    let text = parameters.as_str().ok_or_else(|| KvError::InvalidJson {
+        field: Some(String::from("parameters")),
+        message: String::from("expected a string (parKVFinder parameters file)"),
+    })?;
+    if input.contains_key("settings") {
+        return Err(KvError::InvalidParameters(String::from(
+            "`parameters` and `settings` cannot be sent together",
+        )));
+    }
+    input.insert(String::from("settings"), super::settings_from_parameters(text)?);
+    Ok(value)
+}
+
+/// Validate an input and send it to queue, unless a job with the same
+/// parameters (hash -> tag id) is already there.
+async fn submit(
+    queue: &dyn JobQueue,
+    policy: &ValidationPolicy,
+    decompress_limit: DecompressLimit,
+    value: serde_json::Value,
+) -> Result<(Submission, Stats), KvError> {
+    let (value, encoding) = decoded(value, decompress_limit)?;
+    // json input values to input struct (with boxes computed from box_selection)
+    let input = from_json::<Input>(with_parameters(value)?)?.with_selected_box();
+    // check input values (pdb, pdb_ligand, ...) and sanitize the structure if requested
+    let (model, serial) = input.check(policy)?;
+    let stats = model.stats();
+    let input = input.canonical(serial).sanitized(&model)?;
+    let tag_id = tag_id(&input)?;
     // compress pdb data to reduce queue memory usage.
     let compressed_input = Input {
-        pdb: super::compress(&input.pdb).expect("compression error"),
-        pdb_ligand: match input.pdb_ligand {
-            Some(lig) => Some(super::compress(&lig).expect("compression error")),
+        pdb: compress(&input.pdb)?,
+        pdb_ligand: match &input.pdb_ligand {
+            Some(lig) => Some(compress(lig)?),
             None => None,
         },
         ..input
     };
     let data = Data {
-        // create a tag using function hash64 applied to input (unique value per input)
-        tags: [city::hash64(serde_json::to_string(&compressed_input).unwrap()).to_string()],
+        tags: [tag_id],
         input: compressed_input,
     };
-    // closure to sends data to queue
-    let create_job = || {
-        let client = reqwest::Client::new();
-
-        // get current queue size (number of jobs in queue) to calculate estimated time to finish the job (max time to finish = queue size * timeout)
-        let queue_size = client
-            .get("http://ocypod:8023/queue/kvfinder/size")
-            .send()
-            .unwrap()
-            .text()
-            .unwrap();
-
-        let response = client
-            .post("http://ocypod:8023/queue/kvfinder/job")
-            .json(&data)
-            .send();
+    // if err, problem in queue server
+    let submission = match get_job(queue, data.tags[0].clone(), encoding, &CavityFilter::default()).await? {
+        // if job with this tag is in queue and did not fail, return job
+        Some(j) if j.status != "failed" => Submission::Existing(Box::new(j)),
+        // if job with this tag is not found on queue (or failed), create job
+        _ => create_job(queue, &data).await?,
+    };
+    Ok((submission, stats))
+}
 
-        match response {
-            // if job is created, return job id and queue size (number of jobs in queue)
-            Ok(_) => HttpResponse::Ok().json(json!({"id": data.tags[0], "queue_size": queue_size})),
-            Err(e) => HttpResponse::InternalServerError().body(format!("{:?}", e)),
+/// POST /batch
+/// Receives a list of inputs (`inputs`) and/or a list of structures (`pdbs`)
+/// submitted with the same `settings`. Each one is handled as in POST /create;
+/// invalid ones are reported with their errors without rejecting the others.
+/// Responds with a batch id (to poll the status of all jobs with GET /batch/:id)
+/// and the id (or error) of each job, in submission order.
+pub async fn batch(
+    batch_input: web::Json<serde_json::Value>,
+    queue: web::Data<dyn JobQueue>,
+    policy: web::Data<ValidationPolicy>,
+    decompress_limit: web::Data<DecompressLimit>,
+) -> Result<HttpResponse, KvError> {
+    let queue = queue.get_ref();
+    let batch_input = from_json::<BatchInput>(batch_input.into_inner())?;
+    let mut values = batch_input.inputs;
+    for pdb in batch_input.pdbs {
+        let mut value = json!({ "pdb": pdb });
+        if let Some(settings) = &batch_input.settings {
+            value["settings"] = settings.clone();
         }
-    };
-    let job = get_job(data.tags[0].clone());
-    match job {
-        // if err, problem in queue server
-        Err(e) => HttpResponse::InternalServerError().body(format!("{:?}", e)),
-        // if job with this tag is in queue, return job
-        Ok(Some(j)) => HttpResponse::Ok().json(j),
-        // if job with this tag is not found on queue, create job
-        Ok(None) => create_job(), //format!("{} created", tag_id),
+        if let Some(parameters) = &batch_input.parameters {
+            value["parameters"] = json!(parameters);
+        }
+        if !batch_input.encoding.is_text() {
+            value["encoding"] = json!(batch_input.encoding);
+        }
+        values.push(value);
+    }
+    if values.is_empty() || values.len() > policy.max_batch_size {
+        return Err(KvError::Invalid(vec![Violation::new(
+            ErrorCode::BatchSizeRange,
+            "inputs",
+            &format!(
+                "A batch must have between 1 and {} inputs (received {})",
+                policy.max_batch_size,
+                values.len()
+            ),
+        )]));
+    }
+    let mut jobs = Vec::with_capacity(values.len());
+    let mut tags = Vec::new();
+    for value in values {
+        match submit(queue, &policy, **decompress_limit, value).await {
+            Ok((submission, stats)) => {
+                let (id, status) = match submission {
+                    Submission::Existing(j) => (j.id, j.status),
+                    Submission::Created { id, .. } => (id, "queued".to_string()),
+                };
+                jobs.push(json!({"id": id, "status": status, "stats": stats}));
+                tags.push(id);
+            }
+            // the queue server is not working, no job can be submitted
+            Err(e @ KvError::Queue(_)) => return Err(e),
+            Err(e) => jobs.push(json!({"id": null, "error": e.to_json()})),
+        }
+    }
+    // batch id: hash of the jobs it follows (same jobs, same batch)
+    let batch_id = format!("batch-{:x}", Sha256::digest(tags.join(",").as_bytes()));
+    if queue.find_batch(&batch_id).await?.is_none() {
+        queue.save_batch(&batch_id, &tags).await?;
     }
+    Ok(HttpResponse::Ok().json(json!({"batch_id": batch_id, "jobs": jobs})))
 }
 
-fn get_input(tag_id: String) -> Result<Option<JobInput>, reqwest::Error> {
-    let queue_id = get_queue_id(&tag_id);
-
-    let get_job_input = |queue_id| {
-        let url = format!(
-            "http://ocypod:8023/job/{}?fields=input,created_at",
-            queue_id
-        );
-        // let url = format!("http://localhost:8023/job/{}?fields=input,created_at", queue_id);
-        let mut job_input: JobInput = reqwest::get(url.as_str())?.json()?;
-
-        job_input.id = tag_id;
-        job_input.input.pdb = super::decompress(&job_input.input.pdb).expect("decompression error");
-        job_input.input.pdb_ligand = match job_input.input.pdb_ligand {
-            Some(lig) => Some(super::decompress(&lig).expect("decompression error")),
+/// GET /batch/:batch_id
+/// Responds with the status of each job of a batch (jobs removed from queue
+/// after expiring are "not_found") and the number of jobs by status.
+/// Job results are retrieved with GET /:id.
+pub async fn ask_batch(batch_id: web::Path<String>, queue: web::Data<dyn JobQueue>) -> Result<HttpResponse, KvError> {
+    let batch_id = batch_id.into_inner();
+    let queue = queue.get_ref();
+    let tags = match queue.find_batch(&batch_id).await? {
+        Some(tags) => tags,
+        None => return Err(KvError::BatchNotFound(batch_id)),
+    };
+    let mut jobs = Vec::with_capacity(tags.len());
+    let mut progress: BTreeMap<String, usize> = BTreeMap::new();
+    for tag_id in tags {
+        let status = match queue.find_by_tag(&tag_id).await? {
+            Some(queue_id) => queue.status(queue_id).await?,
             None => None,
-        };
+        }
+        .unwrap_or_else(|| "not_found".to_string());
+        *progress.entry(status.clone()).or_default() += 1;
+        jobs.push(json!({"id": tag_id, "status": status}));
+    }
+    Ok(HttpResponse::Ok().json(json!({
+        "batch_id": batch_id,
+        "total": jobs.len(),
+        "progress": progress,
+        "jobs": jobs,
+    })))
+}
 
-        Ok(Some(job_input))
-    };
+/// Tag id of a job: SHA-256 (lowercase hex) of the json serialization of the
+/// canonical input (see `Input::canonical`), before compression. Fields are
+/// serialized in declaration order and optional fields that are not set are
+/// omitted, so ids do not change when new optional fields are added.
+fn tag_id(input: &Input) -> Result<String, KvError> {
+    let json = serde_json::to_string(input).map_err(|e| KvError::Internal(e.to_string()))?;
+    Ok(format!("{:x}", Sha256::digest(json.as_bytes())))
+}
+
+/// Send data to queue
+async fn create_job(queue: &dyn JobQueue, data: &Data) -> Result<Submission, KvError> {
+    // get current queue size (number of jobs in queue) to calculate estimated time to finish the job (max time to finish = queue size * timeout)
+    let queue_size = queue.size().await?;
+    queue.enqueue(data).await?;
+    Ok(Submission::Created {
+        id: data.tags[0].clone(),
+        queue_size,
+    })
+}
 
-    match queue_id {
-        Err(e) => Err(e),
+async fn get_input(queue: &dyn JobQueue, tag_id: String, encoding: Encoding) -> Result<Option<JobInput>, KvError> {
+    let record = match queue.find_by_tag(&tag_id).await? {
+        Some(queue_id) => queue.get_input(queue_id).await?,
         // if queue_id is None (tag_id not found)
-        Ok(None) => return Ok(None),
-        // return job input in json
-        Ok(Some(queue_id)) => return get_job_input(queue_id),
-    }
+        None => None,
+    };
+    let r = match record {
+        Some(r) => r,
+        None => return Ok(None),
+    };
+    let input = match encoding {
+        Encoding::Zstd => r.input,
+        Encoding::Text => Input {
+            pdb: decompress(&r.input.pdb)?,
+            pdb_ligand: match &r.input.pdb_ligand {
+                Some(lig) => Some(decompress(lig)?),
+                None => None,
+            },
+            ..r.input
+        },
+    };
+    Ok(Some(JobInput {
+        id: tag_id,
+        input,
+        created_at: r.created_at,
+        encoding,
+    }))
 }
 
 // GET /retrieve-input/{:id}
 // Responds with id, 'created_at' and input: pdb, pdb_ligand, kv_settings
-pub async fn retrieve_input(id: web::Path<String>) -> impl Responder {
+// With `?encoding=zstd` pdb and pdb_ligand are sent compressed (see `Encoding`).
+pub async fn retrieve_input(
+    id: web::Path<String>,
+    query: web::Query<EncodingQuery>,
+    queue: web::Data<dyn JobQueue>,
+) -> Result<HttpResponse, KvError> {
     let tag_id = id.into_inner();
-    let job_input = get_input(tag_id);
-    match job_input {
-        Err(e) => HttpResponse::InternalServerError().body(format!("{:?}", e)),
-        Ok(None) => HttpResponse::NotFound().finish(),
-        Ok(Some(j)) => HttpResponse::Ok().json(j),
+    match get_input(queue.get_ref(), tag_id.clone(), query.encoding).await? {
+        None => Err(KvError::JobNotFound(tag_id)),
+        Some(j) => Ok(HttpResponse::Ok().json(j)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kvweb::config::Config;
+    use crate::kvweb::queue::{MemoryQueue, OcypodQueue};
+    use crate::kvweb::FailureKind;
+    use actix_web::{http::StatusCode, rt, test, App, HttpServer};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    const PDB_1FMO: &str = include_str!("../../../examples/1FMO.pdb");
+
+    /// Service with the routes, limits and error handlers of kv_server
+    /// (default configuration) and `queue`
+    macro_rules! service {
+        ($queue:expr) => {{
+            let config = Config::default();
+            let queue: Arc<dyn JobQueue> = $queue;
+            test::init_service(
+                App::new()
+                    .app_data(web::Data::from(queue))
+                    .app_data(web::Data::new(config.validation.clone()))
+                    .app_data(
+                        web::JsonConfig::default()
+                            .limit(config.server.json_limit)
+                            .error_handler(json_error_handler),
+                    )
+                    .app_data(web::QueryConfig::default().error_handler(query_error_handler))
+                    .service(web::scope("/v1").configure(routes(&config.server))),
+            )
+            .await
+        }};
+    }
+
+    fn memory_queue() -> Arc<MemoryQueue> {
+        Arc::new(MemoryQueue::new(Duration::from_secs(60)))
+    }
+
+    #[actix_web::test]
+    async fn created_job_is_found_by_its_id() {
+        let queue = memory_queue();
+        let app = service!(queue.clone());
+        let req = test::TestRequest::post()
+            .uri("/v1/create")
+            .set_json(json!({ "pdb": PDB_1FMO }))
+            .to_request();
+        let created: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        let id = created["id"].as_str().unwrap().to_string();
+        assert_eq!(created["queue_size"], 0);
+        assert_eq!(created["stats"]["atoms"], 2792);
+        assert_eq!(queue.size().await.unwrap(), 1);
+
+        let req = test::TestRequest::get().uri(&format!("/v1/{}", id)).to_request();
+        let job: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(job["id"], id.as_str());
+        assert_eq!(job["status"], "queued");
+        assert_eq!(job["output"], serde_json::Value::Null);
+
+        // the same input is not queued again
+        let req = test::TestRequest::post()
+            .uri("/v1/create")
+            .set_json(json!({ "pdb": PDB_1FMO }))
+            .to_request();
+        let existing: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(existing["id"], id.as_str());
+        assert_eq!(existing["status"], "queued");
+        assert_eq!(queue.size().await.unwrap(), 1);
+    }
+
+    #[actix_web::test]
+    async fn failed_job_is_queued_again() {
+        let queue = memory_queue();
+        let app = service!(queue.clone());
+        let req = test::TestRequest::post()
+            .uri("/v1/create")
+            .set_json(json!({ "pdb": PDB_1FMO }))
+            .to_request();
+        let created: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        let id = created["id"].as_str().unwrap().to_string();
+        let job = queue.next_job().await.unwrap().unwrap();
+        let failure = Failure {
+            reason: String::from("parKVFinder exited with code 1"),
+            kind: FailureKind::ExitCode,
+            exit_code: Some(1),
+            stdout: compress(&String::new()).unwrap(),
+            stderr: compress(&String::new()).unwrap(),
+            log: None,
+        };
+        queue.fail(job.id, failure).await.unwrap();
+
+        let req = test::TestRequest::post()
+            .uri("/v1/create")
+            .set_json(json!({ "pdb": PDB_1FMO }))
+            .to_request();
+        let resubmitted: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(resubmitted["id"], id.as_str());
+        assert_eq!(resubmitted["queue_size"], 0);
+        assert_eq!(queue.size().await.unwrap(), 1);
+
+        let req = test::TestRequest::get().uri(&format!("/v1/{}", id)).to_request();
+        let job: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(job["status"], "queued");
+        assert_eq!(job.get("failure"), None);
+    }
+
+    #[actix_web::test]
+    async fn equivalent_inputs_have_the_id_of_the_readme() {
+        let queue = memory_queue();
+        let app = service!(queue.clone());
+        let padded: String = PDB_1FMO.lines().map(|l| format!("{}  \t\n", l)).collect();
+        let inputs = [
+            json!({ "pdb": PDB_1FMO }),
+            json!({ "pdb": PDB_1FMO.replace('\n', "\r\n") }),
+            json!({ "pdb": format!("{}\n\n", padded) }),
+            json!({ "pdb": PDB_1FMO, "model": 1 }),
+            json!({ "pdb": PDB_1FMO, "format": "pdb", "settings": {"modes": {"whole_protein_mode": true}} }),
+        ];
+        for input in inputs {
+            let req = test::TestRequest::post().uri("/v1/create").set_json(&input).to_request();
+            let created: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+            assert_eq!(
+                created["id"], "23134a56ec46127a2cb997534e47030fafe69e3f018d3912ff537ec926374f3b",
+                "{}",
+                input.as_object().unwrap().keys().cloned().collect::<Vec<String>>().join(", ")
+            );
+        }
+        assert_eq!(queue.size().await.unwrap(), 1);
+    }
+
+    #[actix_web::test]
+    async fn box_selection_and_its_boxes_have_the_same_id() {
+        let queue = memory_queue();
+        let app = service!(queue.clone());
+        let req = test::TestRequest::post()
+            .uri("/v1/create")
+            .set_json(json!({
+                "pdb": PDB_1FMO,
+                "settings": {"modes": {"whole_protein_mode": false, "box_mode": true}},
+                "box_selection": {"residues": {"chain": "E", "residues": [50, 51, 52]}},
+            }))
+            .to_request();
+        let created: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        let id = created["id"].as_str().unwrap();
+
+        let req = test::TestRequest::get().uri(&format!("/v1/retrieve-input/{}", id)).to_request();
+        let stored: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        let input = &stored["input"];
+        assert_eq!(input["box_selection"], serde_json::Value::Null);
+        assert_ne!(input["settings"]["visiblebox"]["p1"]["x"].as_f64(), Some(0.0));
+
+        // the stored input has the computed boxes
+        let req = test::TestRequest::post().uri("/v1/create").set_json(input).to_request();
+        let resubmitted: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(resubmitted["id"], id);
+        assert_eq!(queue.size().await.unwrap(), 1);
+    }
+
+    /// Time the stub Ocypod server takes to answer each request
+    const QUEUE_DELAY: Duration = Duration::from_millis(300);
+
+    /// Ocypod server (its routes used by POST /create) answering after
+    /// `QUEUE_DELAY`, in its own threads. Returns its address.
+    fn slow_ocypod() -> String {
+        async fn delayed(answer: serde_json::Value) -> HttpResponse {
+            rt::time::sleep(QUEUE_DELAY).await;
+            HttpResponse::Ok().json(answer)
+        }
+        let server = HttpServer::new(|| {
+            App::new()
+                .route("/tag/{tag}", web::get().to(|| delayed(json!([]))))
+                .route("/queue/{name}/size", web::get().to(|| delayed(json!(0))))
+                .route("/queue/{name}/job", web::post().to(|| delayed(json!(1))))
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let address = format!("http://{}", server.addrs()[0]);
+        rt::spawn(server.run());
+        address
+    }
+
+    #[actix_web::test]
+    async fn handlers_answer_while_the_queue_is_slow() {
+        let queue = OcypodQueue::new(&slow_ocypod(), "kvfinder");
+        let app = service!(Arc::new(queue));
+        let start = Instant::now();
+        let create = test::call_service(
+            &app,
+            test::TestRequest::post()
+                .uri("/v1/create")
+                .set_json(json!({ "pdb": PDB_1FMO }))
+                .to_request(),
+        );
+        let hello = test::call_service(&app, test::TestRequest::get().uri("/v1/").to_request());
+        futures_util::pin_mut!(create);
+        // POST /create is polled first: with a blocking queue client it would
+        // answer before GET / is handled
+        let resp = tokio::select! {
+            biased;
+            _ = &mut create => panic!("POST /create answered before the queue server"),
+            resp = hello => resp,
+        };
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(start.elapsed() < QUEUE_DELAY);
+        assert_eq!(test::read_body(resp).await, "KVFinder-web service");
+
+        // POST /create answers once the queue server does (tag, size and job requests)
+        let resp = create.await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(start.elapsed() >= 3 * QUEUE_DELAY);
+        let created: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(created["queue_size"], 0);
+    }
+
+    #[actix_web::test]
+    async fn malformed_pdb_records_are_reported_with_their_line() {
+        let atom = "ATOM      1  N   GLU E  13      -6.693 -15.642 -14.858  1.00100.00           N";
+        let cases = [
+            (
+                format!("{}\nATOM      2  CA  GLU E  13      -6.730 -1X.620 -15.897  1.00 84.38           C", atom),
+                "Cannot read atom coordinates at line 2",
+            ),
+            (
+                format!("HEADER    HYDROLASE\nREMARK 1\n{}", atom.replace("  13  ", " 1A3  ")),
+                "Cannot read residue sequence number at line 3",
+            ),
+            (
+                format!("MODEL        1\n{}\nENDMDL\nMODEL        B\n{}\nENDMDL", atom, atom),
+                "Invalid MODEL serial number at line 4",
+            ),
+            (
+                format!("{}\nATOM      2  CA  GLU E  13      -6.730 -14.620", atom),
+                "Cannot read atom coordinates at line 2",
+            ),
+            (format!("{}\nATOM  \n{}", atom, atom), "Cannot read residue sequence number at line 2"),
+        ];
+        let app = service!(memory_queue());
+        for (pdb, message) in cases {
+            let req = test::TestRequest::post()
+                .uri("/v1/create")
+                .set_json(json!({ "pdb": pdb }))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{}", message);
+            let body: serde_json::Value = test::read_body_json(resp).await;
+            assert_eq!(body["code"], "PDB_PARSING");
+            assert_eq!(body["field"], "pdb");
+            assert_eq!(body["message"], format!("Invalid PDB file! {}!", message));
+        }
+    }
+
+    #[actix_web::test]
+    async fn mmcif_values_that_do_not_fit_pdb_columns_are_rejected() {
+        let cif = include_str!("../../../examples/1FMO.cif")
+            .replacen(" 13 GLU E CA 1\n", " 10000 GLU E CA 1\n", 1);
+        let app = service!(memory_queue());
+        let req = test::TestRequest::post()
+            .uri("/v1/create")
+            .set_json(json!({ "pdb": cif }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["code"], "PDB_COLUMN_OVERFLOW");
+        assert_eq!(body["field"], "pdb");
+        assert_eq!(
+            body["message"],
+            "Invalid mmCIF file! Residue sequence number 10000 does not fit in PDB columns 23-26 at line 24! \
+             parKVFinder reads PDB files"
+        );
+    }
+
+    #[actix_web::test]
+    async fn box_selection_violations_are_reported_with_the_others() {
+        let app = service!(memory_queue());
+        let req = test::TestRequest::post()
+            .uri("/v1/create")
+            .set_json(json!({
+                "pdb": PDB_1FMO,
+                "settings": {
+                    "modes": {"whole_protein_mode": false, "box_mode": true},
+                    "probes": {"probe_in": 9.0, "probe_out": 10.0},
+                },
+                "box_selection": {"residues": {"chain": "Z", "residues": [13], "padding": -1.0}},
+            }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        let errors: Vec<(&str, &str)> = body["errors"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|e| (e["code"].as_str().unwrap(), e["field"].as_str().unwrap()))
+            .collect();
+        assert_eq!(
+            errors,
+            [
+                ("PROBE_IN_RANGE", "settings.probes.probe_in"),
+                ("BOX_SELECTION_EMPTY", "box_selection.residues"),
+                ("BOX_PADDING_RANGE", "box_selection.residues.padding"),
+            ]
+        );
+        assert_eq!(body["code"], "PROBE_IN_RANGE");
+    }
+
+    #[actix_web::test]
+    async fn schema_describes_the_fields_read_before_the_input() {
+        let app = service!(memory_queue());
+        let req = test::TestRequest::get().uri("/v1/schema").to_request();
+        let schema: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(schema["title"], "Input");
+        assert_eq!(schema["additionalProperties"], false);
+        assert_eq!(schema["required"], json!(["pdb"]));
+        assert_eq!(schema["properties"]["parameters"]["type"], json!(["string", "null"]));
+        assert_eq!(
+            schema["properties"]["encoding"]["anyOf"][0]["$ref"],
+            "#/definitions/Encoding"
+        );
+        assert_eq!(schema["definitions"]["Encoding"]["enum"], json!(["text", "zstd"]));
+        assert!(schema["properties"]["settings"].is_object());
+    }
+
+    #[actix_web::test]
+    async fn compressed_structures_are_decompressed_up_to_the_json_limit() {
+        let app = service!(memory_queue());
+        let pdb = crate::kvweb::compress(&PDB_1FMO.to_string()).unwrap();
+        let req = test::TestRequest::post()
+            .uri("/v1/create")
+            .set_json(json!({ "pdb": pdb, "encoding": "zstd" }))
+            .to_request();
+        let created: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(created["id"], "23134a56ec46127a2cb997534e47030fafe69e3f018d3912ff537ec926374f3b");
+
+        // a few kilobytes expanding beyond json_limit
+        let limit = Config::default().server.json_limit;
+        let bomb = crate::kvweb::compress(&"A".repeat(limit + 1)).unwrap();
+        assert!(bomb.len() < 10_000);
+        let message = format!("Invalid json: cannot decompress zstd data: decompressed data larger than {} bytes", limit);
+        let req = test::TestRequest::post()
+            .uri("/v1/create")
+            .set_json(json!({ "pdb": pdb, "pdb_ligand": bomb, "encoding": "zstd" }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["code"], "INVALID_JSON");
+        assert_eq!(body["field"], "pdb_ligand");
+        assert_eq!(body["message"], message);
+
+        let req = test::TestRequest::post()
+            .uri("/v1/batch")
+            .set_json(json!({ "pdbs": [pdb, bomb], "encoding": "zstd" }))
+            .to_request();
+        let batch: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(batch["jobs"][0]["id"], created["id"]);
+        assert_eq!(batch["jobs"][1]["id"], serde_json::Value::Null);
+        assert_eq!(batch["jobs"][1]["error"]["field"], "pdb");
+        assert_eq!(batch["jobs"][1]["error"]["message"], message);
+    }
+
+    #[actix_web::test]
+    async fn batch_jobs_are_submitted_with_their_ids_or_errors() {
+        let queue = memory_queue();
+        let app = service!(queue.clone());
+        let pdb_1hhp = include_str!("../../../examples/1HHP.pdb");
+        let settings = json!({ "probes": { "probe_in": 1.4, "probe_out": 8.0 } });
+        let batch_input = json!({
+            "inputs": [{ "pdb": PDB_1FMO }, { "pdb": "ATOM  \n" }],
+            "pdbs": [pdb_1hhp],
+            "settings": settings,
+        });
+        let req = test::TestRequest::post().uri("/v1/batch").set_json(&batch_input).to_request();
+        let batch: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        let jobs = batch["jobs"].as_array().unwrap();
+        assert_eq!(jobs.len(), 3);
+        assert_eq!(jobs[0]["id"], "23134a56ec46127a2cb997534e47030fafe69e3f018d3912ff537ec926374f3b");
+        assert_eq!(jobs[0]["status"], "queued");
+        assert_eq!(jobs[0]["stats"]["atoms"], 2792);
+        assert_eq!(jobs[1]["id"], serde_json::Value::Null);
+        assert_eq!(jobs[1]["error"]["code"], "PDB_PARSING");
+        assert_eq!(jobs[1]["error"]["field"], "pdb");
+        assert_eq!(queue.size().await.unwrap(), 2);
+
+        // pdbs are submitted with the settings of the batch
+        let req = test::TestRequest::post()
+            .uri("/v1/create")
+            .set_json(json!({ "pdb": pdb_1hhp, "settings": settings }))
+            .to_request();
+        let created: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(created["id"], jobs[2]["id"]);
+        assert_eq!(created["status"], "queued");
+
+        // the same jobs are the same batch
+        let req = test::TestRequest::post().uri("/v1/batch").set_json(&batch_input).to_request();
+        let resubmitted: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(resubmitted["batch_id"], batch["batch_id"]);
+        assert_eq!(queue.size().await.unwrap(), 2);
+
+        queue.next_job().await.unwrap().unwrap();
+        let uri = format!("/v1/batch/{}", batch["batch_id"].as_str().unwrap());
+        let req = test::TestRequest::get().uri(&uri).to_request();
+        let status: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(status["total"], 2);
+        assert_eq!(status["progress"], json!({ "queued": 1, "running": 1 }));
+        assert_eq!(status["jobs"][0], json!({ "id": jobs[0]["id"], "status": "running" }));
+        assert_eq!(status["jobs"][1], json!({ "id": jobs[2]["id"], "status": "queued" }));
+
+        let req = test::TestRequest::get().uri("/v1/batch/batch-unknown").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn batch_size_is_limited() {
+        let app = service!(memory_queue());
+        let max = Config::default().validation.max_batch_size;
+        for size in [0, max + 1] {
+            let req = test::TestRequest::post()
+                .uri("/v1/batch")
+                .set_json(json!({ "pdbs": vec!["x"; size] }))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+            let body: serde_json::Value = test::read_body_json(resp).await;
+            assert_eq!(body["code"], "BATCH_SIZE_RANGE");
+            assert_eq!(body["field"], "inputs");
+            assert_eq!(
+                body["message"],
+                format!("A batch must have between 1 and {} inputs (received {})", max, size)
+            );
+        }
+        let req = test::TestRequest::post()
+            .uri("/v1/batch")
+            .set_json(json!({ "pdbs": vec!["x"; max] }))
+            .to_request();
+        let batch: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(batch["jobs"].as_array().unwrap().len(), max);
+    }
+
+    /// Parts (name, file) of a multipart/form-data request
+    type Parts<'a> = &'a [(&'a str, &'a [u8])];
+
+    /// multipart/form-data request to POST /v1/create with `parts`
+    fn upload(parts: Parts) -> test::TestRequest {
+        let mut body = Vec::new();
+        for (name, file) in parts {
+            body.extend_from_slice(
+                format!(
+                    "--BOUNDARY\r\nContent-Disposition: form-data; name=\"{}\"; filename=\"{}\"\r\n\r\n",
+                    name, name
+                )
+                .as_bytes(),
+            );
+            body.extend_from_slice(file);
+            body.extend_from_slice(b"\r\n");
+        }
+        body.extend_from_slice(b"--BOUNDARY--\r\n");
+        test::TestRequest::post()
+            .uri("/v1/create")
+            .insert_header((header::CONTENT_TYPE, "multipart/form-data; boundary=BOUNDARY"))
+            .set_payload(body)
+    }
+
+    fn gzip(text: &str) -> Vec<u8> {
+        use std::io::Write;
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(text.as_bytes()).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[actix_web::test]
+    async fn uploaded_files_have_the_id_of_the_json_input() {
+        let app = service!(memory_queue());
+        let req = test::TestRequest::post()
+            .uri("/v1/create")
+            .set_json(json!({
+                "pdb": PDB_1FMO,
+                "settings": { "probes": { "probe_in": 1.4, "probe_out": 8.0 } },
+            }))
+            .to_request();
+        let created: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+
+        let settings = "[probes]\nprobe_in = 1.4\nprobe_out = 8.0\n";
+        let req = upload(&[("protein", &gzip(PDB_1FMO)), ("settings", settings.as_bytes())]).to_request();
+        let uploaded: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(uploaded["id"], created["id"]);
+        assert_eq!(uploaded["status"], "queued");
+    }
+
+    #[actix_web::test]
+    async fn invalid_uploads_are_rejected() {
+        let app = service!(memory_queue());
+        let limit = Config::default().server.upload_limit;
+        let large = gzip(&"A".repeat(limit + 1));
+        let pdb = PDB_1FMO.as_bytes();
+        let cases: [(Parts, &str, String); 3] = [
+            (
+                &[("protein", pdb), ("protein", pdb)],
+                "protein",
+                String::from("duplicated part"),
+            ),
+            (
+                &[("protein", pdb), ("structure", pdb)],
+                "structure",
+                String::from("unknown part, expected `protein`, `ligand`, `settings` or `parameters`"),
+            ),
+            (
+                &[("protein", &large)],
+                "protein",
+                format!("file larger than {} bytes", limit),
+            ),
+        ];
+        for (parts, field, message) in cases {
+            let resp = test::call_service(&app, upload(parts).to_request()).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{}", message);
+            let body: serde_json::Value = test::read_body_json(resp).await;
+            assert_eq!(body["code"], "INVALID_UPLOAD");
+            assert_eq!(body["field"], field);
+            assert_eq!(body["message"], format!("Invalid upload: {}", message));
+        }
+    }
+
+    #[actix_web::test]
+    async fn unknown_job_is_not_found() {
+        let app = service!(memory_queue());
+        let req = test::TestRequest::get().uri("/v1/0123abcd").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["code"], "JOB_NOT_FOUND");
     }
 }
@@ -1,87 +1,250 @@
-use super::{Input, Output};
-use reqwest;
+use super::config::WorkerConfig;
+use super::queue::{JobQueue, QueueError};
+use super::{Failure, FailureKind, Input, Output};
 use serde::{Deserialize, Serialize};
 use std::fs;
 use std::fs::{create_dir, File};
+use std::future::Future;
 use std::io;
-use std::io::Write;
+use std::io::{Read, Write};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
 use std::path::Path;
-use std::process::Command;
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+use tokio::{task, time};
 use toml;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct JobInput {
     pub id: u32,
-    input: Input,
-}
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct JobOutput {
-    status: String,
-    output: Output,
-}
-
-pub struct Config {
-    pub kv_path: String,
-    pub job_path: String,
+    pub input: Input,
 }
 
 impl JobInput {
     /// Save config file
-    fn save(&self, config: &Config) -> Result<(), io::Error> {
-        self.input.save(self.id, &config)?;
+    fn save(&self, config: &WorkerConfig) -> Result<(), io::Error> {
+        self.input.save(self.id, config)?;
         Ok(())
     }
 
     /// Call parkvfinder command and get results.
-    fn run(&self, config: &Config) -> Result<Output, io::Error> {
-        let kvfinder = Command::new(format!("{}/parKVFinder", config.kv_path))
+    /// If parKVFinder cannot run, fails, times out or does not write its
+    /// results, returns the reason with the end of its stdout and stderr and
+    /// its log.
+    fn run(&self, config: &WorkerConfig) -> Result<Output, Failure> {
+        let timeout = humantime::parse_duration(&config.timeout)
+            .map_err(|e| Failure::not_run(format!("invalid worker timeout: {}", e)))?;
+        let mut command = Command::new(format!("{}/parKVFinder", config.kv_path));
+        command
             .current_dir(format!("{}/{}", config.job_path, self.id))
             .arg("-p")
-            .arg("params.toml")
-            .status()
-            .expect("failed to execute KVFinder process");
-        println!("process exited with: {}", kvfinder);
-        if kvfinder.success() {
-            // read results from files and compress
-            let kv_pdb_output = super::compress(&fs::read_to_string(format!(
-                "{}/{}/KV_Files/KVFinderWeb/KVFinderWeb.KVFinder.output.pdb",
-                config.job_path, self.id
-            ))?).expect("compression error");
-            let kv_report = super::compress(&fs::read_to_string(format!(
-                "{}/{}/KV_Files/KVFinderWeb/KVFinderWeb.KVFinder.results.toml",
-                config.job_path, self.id
-            ))?).expect("compression error");
-            let kv_log = super::compress(&fs::read_to_string(format!(
-                "{}/{}/KV_Files/KVFinder.log",
-                config.job_path, self.id
-            ))?).expect("compression error");
-            let output = Output {
-                pdb_kv: kv_pdb_output,
-                report: kv_report,
-                log: kv_log,
+            .arg("params.toml");
+        let kvfinder = execute(command, config, timeout)
+            .map_err(|e| Failure::not_run(format!("cannot run parKVFinder: {}", e)))?;
+        let log = fs::read_to_string(format!("{}/{}/KV_Files/KVFinder.log", config.job_path, self.id));
+        let exit_code = kvfinder.status.and_then(|s| s.code());
+        let failure = |kind: FailureKind, reason: String| {
+            Failure::new(
+                kind,
+                reason,
+                exit_code,
+                &kvfinder.stdout,
+                &kvfinder.stderr,
+                log.as_ref().ok().map(String::as_str),
+            )
+        };
+        let status = match kvfinder.status {
+            Some(status) => status,
+            None => {
+                println!("job {}: process timed out after {}", self.id, config.timeout);
+                return Err(failure(
+                    FailureKind::Timeout,
+                    format!("parKVFinder timed out after {}", config.timeout),
+                ));
+            }
+        };
+        println!("job {}: process exited with: {}", self.id, status);
+        if !status.success() {
+            let (kind, reason) = match (status.code(), status.signal()) {
+                (Some(code), _) => (FailureKind::ExitCode, format!("parKVFinder exited with code {}", code)),
+                (None, Some(libc::SIGXCPU)) => (
+                    FailureKind::CpuLimit,
+                    String::from("parKVFinder exceeded the CPU time limit"),
+                ),
+                (None, Some(signal)) => (FailureKind::Signal, format!("parKVFinder was killed by signal {}", signal)),
+                (None, None) => (FailureKind::Signal, String::from("parKVFinder was killed")),
             };
-            println!("KVFinder OK");
-            return Ok(output);
-        } else {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                "oh no! check if variable KVFinder_PATH was set",
-            ));
+            return Err(failure(kind, reason));
+        }
+        // read results from files and compress
+        let output = (|| -> Result<Output, io::Error> {
+            Ok(Output {
+                pdb_kv: super::compress(&fs::read_to_string(format!(
+                    "{}/{}/KV_Files/KVFinderWeb/KVFinderWeb.KVFinder.output.pdb",
+                    config.job_path, self.id
+                ))?)?,
+                report: super::compress(&fs::read_to_string(format!(
+                    "{}/{}/KV_Files/KVFinderWeb/KVFinderWeb.KVFinder.results.toml",
+                    config.job_path, self.id
+                ))?)?,
+                log: super::compress(log.as_ref().map_err(|e| io::Error::new(e.kind(), e.to_string()))?)?,
+            })
+        })()
+        .map_err(|e| failure(FailureKind::ExitCode, format!("cannot read parKVFinder results: {}", e)))?;
+        println!("job {}: KVFinder OK", self.id);
+        Ok(output)
+    }
+}
+
+/// Finished (or killed) parKVFinder process
+struct Execution {
+    /// None if the process timed out
+    status: Option<ExitStatus>,
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+}
+
+/// Interval between checks of a running parKVFinder process
+const WAIT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Run `command` with the thread count and resource limits of `config`,
+/// capturing its stdout and stderr. It runs in its own process group, killed
+/// (with any child process) if it takes longer than `timeout`.
+fn execute(mut command: Command, config: &WorkerConfig, timeout: Duration) -> Result<Execution, io::Error> {
+    command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .process_group(0);
+    let threads = job_threads(config);
+    if threads > 0 {
+        command.env("OMP_NUM_THREADS", threads.to_string());
+    }
+    let (memory_limit, cpu_time_limit) = (config.memory_limit, config.cpu_time_limit);
+    if memory_limit > 0 || cpu_time_limit > 0 {
+        let limit = |soft: u64, hard: u64| libc::rlimit {
+            rlim_cur: soft as libc::rlim_t,
+            rlim_max: hard as libc::rlim_t,
+        };
+        // Safety: the closure only calls setrlimit, which is async-signal-safe
+        unsafe {
+            command.pre_exec(move || {
+                if memory_limit > 0 && libc::setrlimit(libc::RLIMIT_AS, &limit(memory_limit, memory_limit)) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                // SIGXCPU at the soft limit tells this limit from other kills (SIGKILL at the hard limit)
+                if cpu_time_limit > 0 && libc::setrlimit(libc::RLIMIT_CPU, &limit(cpu_time_limit, cpu_time_limit + 1)) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
         }
     }
+    let mut child = command.spawn()?;
+    // pipes are read while the process runs, so it does not block on a full pipe
+    let stdout = read_to_end(child.stdout.take());
+    let stderr = read_to_end(child.stderr.take());
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break Some(status);
+        }
+        if Instant::now() >= deadline {
+            // Safety: kill has no memory safety requirements; a negative pid
+            // is the process group of the child
+            unsafe {
+                libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+            }
+            child.wait()?;
+            break None;
+        }
+        thread::sleep(WAIT_INTERVAL);
+    };
+    Ok(Execution {
+        status,
+        stdout: stdout.join().unwrap_or_default(),
+        stderr: stderr.join().unwrap_or_default(),
+    })
+}
+
+/// Threads of a parKVFinder run: worker.threads or, if it is not set and jobs
+/// run concurrently, the cores shared among them. 0 leaves it to parKVFinder.
+fn job_threads(config: &WorkerConfig) -> usize {
+    if config.threads > 0 || config.concurrency <= 1 {
+        return config.threads;
+    }
+    let cores = thread::available_parallelism().map_or(1, |n| n.get());
+    (cores / config.concurrency).max(1)
+}
+
+/// Read a pipe of a child process in a thread
+fn read_to_end<R: Read + Send + 'static>(pipe: Option<R>) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut bytes = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut bytes);
+        }
+        bytes
+    })
+}
+
+/// Bytes kept from the end of parKVFinder stdout and stderr
+const MAX_CAPTURED_OUTPUT: usize = 64 * 1024;
+
+impl Failure {
+    fn new(
+        kind: FailureKind,
+        reason: String,
+        exit_code: Option<i32>,
+        stdout: &[u8],
+        stderr: &[u8],
+        log: Option<&str>,
+    ) -> Failure {
+        let tail = |bytes: &[u8]| {
+            let bytes = &bytes[bytes.len().saturating_sub(MAX_CAPTURED_OUTPUT)..];
+            compress_lossy(&String::from_utf8_lossy(bytes))
+        };
+        Failure {
+            reason,
+            kind,
+            exit_code,
+            stdout: tail(stdout),
+            stderr: tail(stderr),
+            log: log.map(compress_lossy),
+        }
+    }
+
+    /// Failure of a job whose parKVFinder did not run
+    fn not_run(reason: String) -> Failure {
+        Failure::new(FailureKind::NotRun, reason, None, &[], &[], None)
+    }
+}
+
+/// Compress text of a failure. Failing to compress it must not hide the
+/// failure, so the text is dropped instead.
+fn compress_lossy(text: &str) -> String {
+    super::compress(&text.to_string()).unwrap_or_default()
 }
 
 impl Input {
     // save files to parkvfinder process them.
-    fn save(&self, id: u32, config: &Config) -> Result<(), io::Error> {
+    fn save(&self, id: u32, config: &WorkerConfig) -> Result<(), io::Error> {
         let dir = format!("{}/{}", config.job_path, id);
+        // The directory of a retried job, or of a job whose id was used
+        // before a restart of the queue, holds files of that earlier run.
+        match fs::remove_dir_all(&dir) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+            _ => (),
+        }
         match create_dir(&dir) {
             Err(err) => Err(err),
             Ok(_) => {
-                self.save_parameters(&dir, &config)?;
+                self.save_parameters(&dir, config)?;
                 self.save_pdb(&dir)?;
-                if let Some(_) = self.pdb_ligand {
+                if self.pdb_ligand.is_some() {
                     self.save_pdb_ligand(&dir)?;
                 }
                 Ok(())
@@ -89,11 +252,11 @@ impl Input {
         }
     }
 
-    fn save_parameters(&self, dir: &str, config: &Config) -> Result<(), io::Error> {
+    fn save_parameters(&self, dir: &str, config: &WorkerConfig) -> Result<(), io::Error> {
         let params = super::KVParameters {
             title: String::from("KVFinder-worker parameters"),
             files_path: super::KVFilesPath {
-                dictionary: String::from(format!("{}/dictionary", config.kv_path)),
+                dictionary: format!("{}/dictionary", config.kv_path),
                 pdb: String::from("./protein.pdb"),
                 ligand: String::from("./ligand.pdb"),
                 output: String::from("./"),
@@ -115,42 +278,292 @@ impl Input {
         let filename = format!("{}/protein.pdb", dir);
         let path = Path::new(&filename);
         let mut file = File::create(path)?;
-        writeln!(file, "{}", super::decompress(&self.pdb).expect("decompression error"))?;
+        let mut pdb = decompress(&self.pdb)?;
+        // format is set by the webserver; it is detected for jobs created without it
+        let is_mmcif = match self.format {
+            Some(format) => format == super::StructureFormat::Mmcif,
+            None => super::mmcif::is_mmcif(&pdb),
+        };
+        if is_mmcif {
+            pdb = super::mmcif::to_pdb(&pdb, self.model).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        }
+        // parKVFinder reads every model of a multi-model file, so only the chosen one is saved
+        writeln!(file, "{}", super::pdb::select_model(&pdb, self.model))?;
         Ok(())
     }
 
     fn save_pdb_ligand(&self, dir: &str) -> Result<(), io::Error> {
         let filename = format!("{}/ligand.pdb", dir);
         let path = Path::new(&filename);
-        let mut file = File::create(&path)?;
+        let mut file = File::create(path)?;
         if let Some(pdb_ligand) = &self.pdb_ligand {
-            writeln!(file, "{}", super::decompress(&pdb_ligand).expect("decompression error"))?;
+            writeln!(file, "{}", decompress(pdb_ligand)?)?;
         }
         Ok(())
     }
 }
 
-/// Get next job from queue. Returns Error if there is not a job to process.
-pub fn get_job() -> Result<JobInput, reqwest::Error> {
-    // the queue name "kvfinder" is hardcoded at bin/kv_server.rs
-    let j: JobInput = reqwest::get("http://ocypod:8023/queue/kvfinder/job")?.json()?;
-    Ok(j)
+fn decompress(b64: &String) -> Result<String, io::Error> {
+    super::decompress(b64).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
 }
 
-pub fn process(job: JobInput, config: &Config) -> Result<Output, io::Error> {
-    job.save(&config)?;
-    let output = job.run(&config);
-    output
+/// Get next job from queue. Returns Ok(None) if there is not a job to process.
+pub async fn get_job(queue: &dyn JobQueue) -> Result<Option<JobInput>, QueueError> {
+    queue.next_job().await
 }
 
-pub fn submit_result(id: u32, output: Output) -> Result<u32, reqwest::Error> {
-    let client = reqwest::Client::new();
-    let url = format!("http://ocypod:8023/job/{}", id);
-    let  data = JobOutput {
-        status: String::from("completed"),
-        output,
-    };
+pub fn process(job: JobInput, config: &WorkerConfig) -> Result<Output, Failure> {
+    job.save(config)
+        .map_err(|e| Failure::not_run(format!("cannot save job files: {}", e)))?;
+    job.run(config)
+}
+
+pub async fn submit_result(queue: &dyn JobQueue, id: u32, output: Output) -> Result<u32, QueueError> {
     // update job at queue
-    let _result = client.patch(url.as_str()).json(&data).send()?;
+    queue.complete(id, output).await?;
+    Ok(id)
+}
+
+/// Mark the job as failed, with the reason shown to users.
+pub async fn submit_failure(queue: &dyn JobQueue, id: u32, failure: Failure) -> Result<u32, QueueError> {
+    queue.fail(id, failure).await?;
     Ok(id)
 }
+
+/// Process jobs from `queue`, up to `config.concurrency` at the same time,
+/// until `shutdown` resolves; then stop fetching jobs and return once the
+/// running ones are finished. `poll_interval` is the time to wait when there
+/// is not a job to process (see `WorkerConfig::check`).
+pub async fn run(
+    queue: Arc<dyn JobQueue>,
+    config: WorkerConfig,
+    poll_interval: Duration,
+    shutdown: impl Future<Output = ()>,
+) {
+    let concurrency = config.concurrency;
+    // a job is fetched only when one of the `concurrency` slots is free
+    let slots = Arc::new(Semaphore::new(concurrency));
+    tokio::pin!(shutdown);
+    loop {
+        let slot = tokio::select! {
+            _ = &mut shutdown => break,
+            slot = slots.clone().acquire_owned() => slot.expect("job slots closed"),
+        };
+        // get the next job from queue. If there is not a job to process then wait poll_interval.
+        match get_job(queue.as_ref()).await {
+            Ok(Some(j)) => {
+                let queue = queue.clone();
+                let worker_config = config.clone();
+                task::spawn(async move {
+                    run_job(queue.as_ref(), j.id, move || process(j, &worker_config)).await;
+                    drop(slot);
+                });
+                continue;
+            }
+            //no job to process
+            Ok(None) => {}
+            Err(e) => println!("Error getting job from queue: {}", e),
+        }
+        drop(slot);
+        tokio::select! {
+            _ = &mut shutdown => break,
+            _ = time::sleep(poll_interval) => {}
+        }
+    }
+    // stop fetching jobs and let the running ones finish
+    let running = concurrency - slots.available_permits();
+    if running > 0 {
+        println!("Stopping, waiting for {} running job(s)", running);
+    }
+    let _ = slots.acquire_many(concurrency as u32).await;
+}
+
+/// Failure of a job whose processing thread panicked, with the panic message
+fn thread_failure(e: task::JoinError) -> Failure {
+    let reason = match e.try_into_panic() {
+        Ok(panic) => match (panic.downcast_ref::<&str>(), panic.downcast_ref::<String>()) {
+            (Some(message), _) => format!("worker thread panicked: {}", message),
+            (_, Some(message)) => format!("worker thread panicked: {}", message),
+            _ => String::from("worker thread panicked"),
+        },
+        Err(e) => format!("worker thread failed: {}", e),
+    };
+    Failure::not_run(reason)
+}
+
+/// Process job `id` (`process` runs parKVFinder) and submit the results
+/// (update job at the queue).
+async fn run_job(queue: &dyn JobQueue, id: u32, process: impl FnOnce() -> Result<Output, Failure> + Send + 'static) {
+    // parKVFinder runs in a blocking thread to not stall the queue client.
+    // If the thread panics the job fails, instead of being left running.
+    let processed = task::spawn_blocking(process).await.unwrap_or_else(|e| Err(thread_failure(e)));
+    match processed {
+        Err(failure) => {
+            println!("Error processing job {}: {}", id, failure.reason);
+            if let Err(e) = submit_failure(queue, id, failure).await {
+                println!("Error submitting failure to queue: {}", e);
+            }
+        }
+        Ok(output) => match submit_result(queue, id, output).await {
+            Ok(id) => println!("Job processed successfully: {}", id),
+            Err(e) => println!("Error submitting result to queue: {}", e),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kvweb::queue::{JobRecord, JobResult, MemoryQueue};
+    use crate::kvweb::Data;
+    use std::os::unix::fs::PermissionsExt;
+
+    const PDB: &str = "\
+ATOM      1  N   GLU E  13      -6.693 -15.642 -14.858  1.00100.00           N
+ATOM      2  CA  GLU E  13      -6.730 -14.620 -15.897  1.00 84.38           C
+";
+
+    /// Worker configuration with a fake parKVFinder, a shell script running
+    /// `script` in the job directory.
+    fn fake_kvfinder(name: &str, script: &str) -> WorkerConfig {
+        let dir = std::env::temp_dir().join(format!("kvweb-worker-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("jobs")).unwrap();
+        let program = dir.join("parKVFinder");
+        fs::write(&program, format!("#!/bin/sh\n{}", script)).unwrap();
+        fs::set_permissions(&program, fs::Permissions::from_mode(0o755)).unwrap();
+        WorkerConfig {
+            kv_path: dir.display().to_string(),
+            job_path: dir.join("jobs").display().to_string(),
+            ..WorkerConfig::default()
+        }
+    }
+
+    async fn enqueue(queue: &MemoryQueue) -> u32 {
+        let input: Input = serde_json::from_value(serde_json::json!({
+            "pdb": super::super::compress(&PDB.to_string()).unwrap(),
+        }))
+        .unwrap();
+        let data = Data {
+            tags: [String::from("tag")],
+            input,
+        };
+        queue.enqueue(&data).await.unwrap()
+    }
+
+    /// Run the worker until job `id` is finished
+    async fn run_until_finished(queue: Arc<MemoryQueue>, config: WorkerConfig, id: u32) -> JobRecord {
+        let polled = queue.clone();
+        let finished = async move {
+            while !matches!(polled.status(id).await.unwrap().as_deref(), Some("completed" | "failed")) {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        time::timeout(
+            Duration::from_secs(10),
+            run(queue.clone(), config, Duration::from_millis(10), finished),
+        )
+        .await
+        .expect("job not finished");
+        queue.get(id).await.unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn completed_job_output_is_stored_in_queue() {
+        let config = fake_kvfinder(
+            "completed",
+            "test -f params.toml && test -f protein.pdb || exit 9
+mkdir -p KV_Files/KVFinderWeb
+echo log > KV_Files/KVFinder.log
+echo cavities > KV_Files/KVFinderWeb/KVFinderWeb.KVFinder.output.pdb
+echo results > KV_Files/KVFinderWeb/KVFinderWeb.KVFinder.results.toml
+",
+        );
+        let queue = Arc::new(MemoryQueue::new(Duration::from_secs(60)));
+        let id = enqueue(&queue).await;
+        let record = run_until_finished(queue.clone(), config.clone(), id).await;
+        assert_eq!(record.status, "completed");
+        let output = match record.output {
+            Some(JobResult::Completed(output)) => output,
+            other => panic!("unexpected output {:?}", other),
+        };
+        assert_eq!(decompress(&output.pdb_kv).unwrap(), "cavities\n");
+        assert_eq!(decompress(&output.report).unwrap(), "results\n");
+        assert_eq!(decompress(&output.log).unwrap(), "log\n");
+        let saved = fs::read_to_string(format!("{}/{}/protein.pdb", config.job_path, id)).unwrap();
+        assert!(saved.starts_with(PDB));
+        assert_eq!(queue.size().await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn files_of_an_earlier_job_with_the_same_id_are_removed() {
+        let config = fake_kvfinder("stale", "test -f stale.pdb && exit 9\nexit 2\n");
+        let queue = Arc::new(MemoryQueue::new(Duration::from_secs(60)));
+        let id = enqueue(&queue).await;
+        fs::create_dir(format!("{}/{}", config.job_path, id)).unwrap();
+        fs::write(format!("{}/{}/stale.pdb", config.job_path, id), PDB).unwrap();
+        let record = run_until_finished(queue, config, id).await;
+        let failure = match record.output {
+            Some(JobResult::Failed { failure }) => failure,
+            other => panic!("unexpected output {:?}", other),
+        };
+        assert_eq!(failure.reason, "parKVFinder exited with code 2");
+    }
+
+    #[tokio::test]
+    async fn failed_job_keeps_exit_code_and_stderr() {
+        let config = fake_kvfinder("failed", "echo 'bad grid' >&2\nexit 2\n");
+        let queue = Arc::new(MemoryQueue::new(Duration::from_secs(60)));
+        let id = enqueue(&queue).await;
+        let record = run_until_finished(queue, config, id).await;
+        assert_eq!(record.status, "failed");
+        let failure = match record.output {
+            Some(JobResult::Failed { failure }) => failure,
+            other => panic!("unexpected output {:?}", other),
+        };
+        assert_eq!(failure.reason, "parKVFinder exited with code 2");
+        assert_eq!(failure.kind, FailureKind::ExitCode);
+        assert_eq!(failure.exit_code, Some(2));
+        assert_eq!(decompress(&failure.stderr).unwrap(), "bad grid\n");
+        assert_eq!(failure.log, None);
+    }
+
+    #[tokio::test]
+    async fn job_over_the_timeout_is_killed() {
+        let config = WorkerConfig {
+            timeout: String::from("200ms"),
+            ..fake_kvfinder("timeout", "sleep 5\n")
+        };
+        let queue = Arc::new(MemoryQueue::new(Duration::from_secs(60)));
+        let id = enqueue(&queue).await;
+        let start = Instant::now();
+        let record = run_until_finished(queue, config, id).await;
+        // sleep keeps stdout open, so the job ends early only if it is killed too
+        assert!(start.elapsed() < Duration::from_secs(3), "job took {:?}", start.elapsed());
+        let failure = match record.output {
+            Some(JobResult::Failed { failure }) => failure,
+            other => panic!("unexpected output {:?}", other),
+        };
+        assert_eq!(failure.reason, "parKVFinder timed out after 200ms");
+        assert_eq!(failure.kind, FailureKind::Timeout);
+        assert_eq!(failure.exit_code, None);
+    }
+
+    #[tokio::test]
+    async fn job_fails_if_its_thread_panics() {
+        let queue = MemoryQueue::new(Duration::from_secs(60));
+        let id = enqueue(&queue).await;
+        let job = queue.next_job().await.unwrap().unwrap();
+        assert_eq!(queue.status(id).await.unwrap().as_deref(), Some("running"));
+        run_job(&queue, job.id, || panic!("cannot process job")).await;
+        let record = queue.get(id).await.unwrap().unwrap();
+        assert_eq!(record.status, "failed");
+        let failure = match record.output {
+            Some(JobResult::Failed { failure }) => failure,
+            other => panic!("unexpected output {:?}", other),
+        };
+        assert_eq!(failure.reason, "worker thread panicked: cannot process job");
+        assert_eq!(failure.kind, FailureKind::NotRun);
+        assert_eq!(failure.exit_code, None);
+    }
+}
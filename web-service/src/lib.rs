@@ -1,13 +1,22 @@
 mod kvweb {
-    pub mod worker;
+    pub mod config;
+    pub mod error;
+    pub mod mmcif;
+    pub mod pdb;
+    pub mod queue;
+    pub mod report;
     pub mod webserver;
+    pub mod worker;
 
     extern crate base64;
     extern crate zstd;
+    use config::ValidationPolicy;
+    use error::{ErrorCode, KvError, Violation};
+    use schemars::JsonSchema;
     use serde::{Deserialize, Serialize};
     use std::io;
+    use std::io::Read;
     use std::error::Error;
-    use std::num::ParseFloatError;
     // use base64::engine::general_purpose::STANDARD;
     use base64::{Engine as _, engine::general_purpose};
   
@@ -28,8 +37,29 @@ mod kvweb {
         ligand: String,
     }
 
-    #[derive(Serialize, Deserialize, Debug, Clone)]
-    #[serde(deny_unknown_fields)]
+    /// Read the settings of a parKVFinder parameters file (a local
+    /// `parameters.toml` or the `params.toml` written by the worker) as a json
+    /// value, to be read and validated as `Input::settings`. parKVFinder names
+    /// the tables `[SETTINGS]` and `[FILES_PATH]`, `KVParameters` names them
+    /// `[settings]` and `[files_path]`. `title` and the files paths are ignored.
+    fn settings_from_parameters(text: &str) -> Result<serde_json::Value, KvError> {
+        let parameters: serde_json::Map<String, serde_json::Value> =
+            toml::from_str(text).map_err(|e| KvError::InvalidParameters(e.to_string()))?;
+        let mut settings = None;
+        for (key, value) in parameters {
+            match key.as_str() {
+                "title" | "FILES_PATH" | "files_path" => {}
+                "SETTINGS" | "settings" if settings.is_none() => settings = Some(value),
+                _ => return Err(KvError::InvalidParameters(format!("unexpected key `{}`", key))),
+            }
+        }
+        settings.ok_or_else(|| KvError::InvalidParameters(String::from("missing [SETTINGS] table")))
+    }
+
+    /// parKVFinder settings. Missing values take parKVFinder defaults (whole
+    /// protein mode, Low resolution, surface mode, Probe In 1.4 and Probe Out 4.0).
+    #[derive(Serialize, Deserialize, Debug, Clone, Default, JsonSchema)]
+    #[serde(default, deny_unknown_fields)]
     struct KVSettings {
         modes: KVSModes,
         step_size: KVSStepSize,
@@ -39,8 +69,8 @@ mod kvweb {
         internalbox: KVSInternalbox,
     }
 
-    #[derive(Serialize, Deserialize, Debug, Clone)]
-    #[serde(deny_unknown_fields)]
+    #[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+    #[serde(default, deny_unknown_fields)]
     struct KVSModes {
         whole_protein_mode: bool,
         box_mode: bool,
@@ -50,38 +80,38 @@ mod kvweb {
         ligand_mode: bool,
     }
 
-    #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+    #[derive(Serialize, Deserialize, PartialEq, Debug, Clone, JsonSchema)]
     #[serde(deny_unknown_fields)]
-    enum KVSResolution {
+    pub enum KVSResolution {
         Low,
         Medium,
         High,
         Off,
     }
 
-    #[derive(Serialize, Deserialize, Debug, Clone)]
-    #[serde(deny_unknown_fields)]
+    #[derive(Serialize, Deserialize, Debug, Clone, Default, JsonSchema)]
+    #[serde(default, deny_unknown_fields)]
     struct KVSStepSize {
         step_size: f64,
     }
 
-    #[derive(Serialize, Deserialize, Debug, Clone)]
-    #[serde(deny_unknown_fields)]
+    #[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+    #[serde(default, deny_unknown_fields)]
     struct KVSProbes {
         probe_in: f64,
         probe_out: f64,
     }
 
-    #[derive(Serialize, Deserialize, Debug, Clone)]
-    #[serde(deny_unknown_fields)]
+    #[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+    #[serde(default, deny_unknown_fields)]
     struct KVSCutoffs {
         volume_cutoff: f64,
         ligand_cutoff: f64,
         removal_distance: f64,
     }
 
-    #[derive(Serialize, Deserialize, Debug, Clone)]
-    #[serde(deny_unknown_fields)]
+    #[derive(Serialize, Deserialize, Debug, Clone, Default, JsonSchema)]
+    #[serde(default, deny_unknown_fields)]
     struct KVSVisiblebox {
         p1: KVSBoxPoint,
         p2: KVSBoxPoint,
@@ -89,8 +119,8 @@ mod kvweb {
         p4: KVSBoxPoint,
     }
 
-    #[derive(Serialize, Deserialize, Debug, Clone)]
-    #[serde(deny_unknown_fields)]
+    #[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+    #[serde(default, deny_unknown_fields)]
     struct KVSInternalbox {
         p1: KVSBoxPoint,
         p2: KVSBoxPoint,
@@ -98,7 +128,7 @@ mod kvweb {
         p4: KVSBoxPoint,
     }
 
-    #[derive(Serialize, Deserialize, Debug, Clone)]
+    #[derive(Serialize, Deserialize, Debug, Clone, Default, JsonSchema)]
     #[serde(deny_unknown_fields)]
     struct KVSBoxPoint {
         x: f64,
@@ -106,6 +136,181 @@ mod kvweb {
         z: f64,
     }
 
+    impl Default for KVSModes {
+        fn default() -> Self {
+            KVSModes {
+                whole_protein_mode: true,
+                box_mode: false,
+                resolution_mode: KVSResolution::Low,
+                surface_mode: true,
+                kvp_mode: false,
+                ligand_mode: false,
+            }
+        }
+    }
+
+    impl Default for KVSProbes {
+        fn default() -> Self {
+            KVSProbes {
+                probe_in: 1.4,
+                probe_out: 4.0,
+            }
+        }
+    }
+
+    impl Default for KVSCutoffs {
+        fn default() -> Self {
+            KVSCutoffs {
+                volume_cutoff: 5.0,
+                ligand_cutoff: 5.0,
+                removal_distance: 2.4,
+            }
+        }
+    }
+
+    impl Default for KVSInternalbox {
+        fn default() -> Self {
+            let point = |x, y, z| KVSBoxPoint { x, y, z };
+            KVSInternalbox {
+                p1: point(-4.0, -4.0, -4.0),
+                p2: point(4.0, -4.0, -4.0),
+                p3: point(-4.0, 4.0, -4.0),
+                p4: point(-4.0, -4.0, 4.0),
+            }
+        }
+    }
+
+    impl KVSVisiblebox {
+        fn corners(&self) -> [&KVSBoxPoint; 4] {
+            [&self.p1, &self.p2, &self.p3, &self.p4]
+        }
+    }
+
+    impl KVSInternalbox {
+        fn corners(&self) -> [&KVSBoxPoint; 4] {
+            [&self.p1, &self.p2, &self.p3, &self.p4]
+        }
+
+        /// Volume (cubic angstroms) of the box with edges p1-p2, p1-p3 and p1-p4
+        fn volume(&self) -> f64 {
+            self.p1.distance(&self.p2) * self.p1.distance(&self.p3) * self.p1.distance(&self.p4)
+        }
+    }
+
+    // Points are also used as vectors in box geometry checks
+    impl KVSBoxPoint {
+        fn distance(&self, other: &KVSBoxPoint) -> f64 {
+            other.sub(self).norm()
+        }
+
+        fn sub(&self, other: &KVSBoxPoint) -> KVSBoxPoint {
+            KVSBoxPoint {
+                x: self.x - other.x,
+                y: self.y - other.y,
+                z: self.z - other.z,
+            }
+        }
+
+        /// self + k * v
+        fn add_scaled(&self, v: &KVSBoxPoint, k: f64) -> KVSBoxPoint {
+            KVSBoxPoint {
+                x: self.x + k * v.x,
+                y: self.y + k * v.y,
+                z: self.z + k * v.z,
+            }
+        }
+
+        fn dot(&self, other: &KVSBoxPoint) -> f64 {
+            self.x * other.x + self.y * other.y + self.z * other.z
+        }
+
+        fn norm(&self) -> f64 {
+            self.dot(self).sqrt()
+        }
+    }
+
+    /// Check that corners p1, p2, p3 and p4 define a rectangular box: p2, p3 and
+    /// p4 are the corners next to p1 along the box x, y and z axes, so edges
+    /// p1-p2, p1-p3 and p1-p4 must be perpendicular and not empty.
+    /// `tolerance` is the distance (angstroms) a corner can be off.
+    fn box_violation(corners: [&KVSBoxPoint; 4], field: &str, name: &str, tolerance: f64) -> Option<Violation> {
+        let edges: Vec<KVSBoxPoint> = corners[1..].iter().map(|c| c.sub(corners[0])).collect();
+        for (i, edge) in edges.iter().enumerate() {
+            if edge.norm() <= tolerance {
+                return Some(Violation::new(
+                    ErrorCode::BoxDegenerate,
+                    &format!("{}.p{}", field, i + 2),
+                    &format!(
+                        "Invalid parameters file! Corner p{} of the {} box must not coincide with corner p1!",
+                        i + 2,
+                        name
+                    ),
+                ));
+            }
+        }
+        for (i, j) in [(0, 1), (0, 2), (1, 2)] {
+            // distance from corner j to the plane through p1 perpendicular to edge i
+            let offset = edges[i].dot(&edges[j]).abs() / edges[i].norm();
+            if offset > tolerance {
+                return Some(Violation::new(
+                    ErrorCode::BoxNotRectangular,
+                    &format!("{}.p{}", field, j + 2),
+                    &format!(
+                        "Invalid parameters file! Corner p{} of the {} box is {:.2} angstroms off: edge p1-p{} must be perpendicular to edge p1-p{}!",
+                        j + 2,
+                        name,
+                        offset,
+                        j + 2,
+                        i + 2
+                    ),
+                ));
+            }
+        }
+        None
+    }
+
+    /// Check that the internal box is the visible box padded by `probe_out` in
+    /// every direction (boxes must be rectangular).
+    fn padding_violation(
+        visiblebox: &KVSVisiblebox,
+        internalbox: &KVSInternalbox,
+        probe_out: f64,
+        tolerance: f64,
+    ) -> Option<Violation> {
+        let visible = visiblebox.corners();
+        // unit vectors of the visible box axes
+        let axes: Vec<KVSBoxPoint> = visible[1..]
+            .iter()
+            .map(|c| {
+                let edge = c.sub(visible[0]);
+                KVSBoxPoint { x: 0.0, y: 0.0, z: 0.0 }.add_scaled(&edge, 1.0 / edge.norm())
+            })
+            .collect();
+        for (n, (corner, internal)) in visible.iter().zip(internalbox.corners()).enumerate() {
+            // p1 moves backwards along every axis, p2 forwards along x and backwards
+            // along y and z, and so on
+            let expected = axes.iter().enumerate().fold((*corner).clone(), |p, (a, axis)| {
+                p.add_scaled(axis, if n == a + 1 { probe_out } else { -probe_out })
+            });
+            let offset = expected.distance(internal);
+            if offset > tolerance {
+                return Some(Violation::new(
+                    ErrorCode::BoxPaddingMismatch,
+                    &format!("settings.internalbox.p{}", n + 1),
+                    &format!(
+                        "Invalid parameters file! Corner p{} of the internal box must be the visible box corner padded by Probe Out ({:.2}, {:.2}, {:.2}), it is {:.2} angstroms off!",
+                        n + 1,
+                        expected.x,
+                        expected.y,
+                        expected.z,
+                        offset
+                    ),
+                ));
+            }
+        }
+        None
+    }
+
     struct PdbBoundaries {
         x_min: f64,
         x_max: f64,
@@ -116,6 +321,47 @@ mod kvweb {
     }
 
     impl PdbBoundaries {
+        /// Boundaries of `atoms` padded by `padding` in each direction
+        fn of(atoms: &[pdb::Atom], padding: f64) -> PdbBoundaries {
+            let c = atoms.iter().fold(
+                PdbBoundaries {
+                    x_min: f64::INFINITY,
+                    x_max: f64::NEG_INFINITY,
+                    y_min: f64::INFINITY,
+                    y_max: f64::NEG_INFINITY,
+                    z_min: f64::INFINITY,
+                    z_max: f64::NEG_INFINITY,
+                },
+                |s, p| PdbBoundaries {
+                    x_min: s.x_min.min(p.x),
+                    x_max: s.x_max.max(p.x),
+                    y_min: s.y_min.min(p.y),
+                    y_max: s.y_max.max(p.y),
+                    z_min: s.z_min.min(p.z),
+                    z_max: s.z_max.max(p.z),
+                },
+            );
+            PdbBoundaries {
+                x_min: c.x_min - padding,
+                x_max: c.x_max + padding,
+                y_min: c.y_min - padding,
+                y_max: c.y_max + padding,
+                z_min: c.z_min - padding,
+                z_max: c.z_max + padding,
+            }
+        }
+
+        /// Corners p1, p2, p3 and p4 of the axis-aligned box
+        fn corners(&self) -> [KVSBoxPoint; 4] {
+            let point = |x, y, z| KVSBoxPoint { x, y, z };
+            [
+                point(self.x_min, self.y_min, self.z_min),
+                point(self.x_max, self.y_min, self.z_min),
+                point(self.x_min, self.y_max, self.z_min),
+                point(self.x_min, self.y_min, self.z_max),
+            ]
+        }
+
         // check if kvbox is inside pdb boundaries
         fn contains(&self, kvbox: &KVSInternalbox) -> bool {
             if self.x_min > kvbox.p1.x
@@ -177,151 +423,657 @@ mod kvweb {
     }
 
     fn decompress(b64: &String) -> Result<String, Box<dyn Error>> {
-        let s =  general_purpose::STANDARD.decode(&b64)?;
+        let s =  general_purpose::STANDARD.decode(b64)?;
         Ok(String::from_utf8(zstd::stream::decode_all(s.as_slice())?)?)
     }
 
+    // Data compressed by clients is decompressed up to `limit` bytes, so a
+    // small payload cannot expand to gigabytes.
+    fn decompress_limited(b64: &str, limit: usize) -> Result<String, Box<dyn Error>> {
+        let s = general_purpose::STANDARD.decode(b64)?;
+        let mut text = Vec::new();
+        zstd::stream::Decoder::new(s.as_slice())?
+            .take(limit as u64 + 1)
+            .read_to_end(&mut text)?;
+        if text.len() > limit {
+            return Err(format!("decompressed data larger than {} bytes", limit).into());
+        }
+        Ok(String::from_utf8(text)?)
+    }
+
 
     #[derive(Serialize, Deserialize)]
-    struct Data {
+    pub struct Data {
         tags: [String; 1],
         input: Input,
     }
 
-    #[derive(Serialize, Deserialize, Debug)]
+    #[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
     #[serde(deny_unknown_fields)]
     pub struct Input {
+        /// parKVFinder settings (default: parKVFinder defaults)
+        #[serde(default)]
         settings: KVSettings,
         pdb: String,
         pdb_ligand: Option<String>,
+        /// MODEL serial number to process in multi-model PDB files (default: first model)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        model: Option<u32>,
+        /// Format of `pdb` (default: detected from its content)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        format: Option<StructureFormat>,
+        /// Rewrite `pdb` before enqueuing the job (default: sent unchanged)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        sanitize: Option<Sanitize>,
+        /// Compute box mode boxes around residues or the ligand (default: boxes
+        /// are read from settings)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        box_selection: Option<BoxSelection>,
+    }
+
+    /// Selection of atoms used to compute the boxes in box mode, e.g.
+    /// `{"residues": {"chain": "A", "residues": [10, 11, 12], "padding": 3.5}}`
+    /// or `{"ligand": {"padding": 5.0}}`.
+    #[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
+    #[serde(rename_all = "lowercase", deny_unknown_fields)]
+    pub enum BoxSelection {
+        /// Residues (numbers) of a chain of `pdb`
+        Residues {
+            chain: String,
+            residues: Vec<i32>,
+            #[serde(default = "default_box_padding")]
+            padding: f64,
+        },
+        /// Atoms of `pdb_ligand`
+        Ligand {
+            #[serde(default = "default_box_padding")]
+            padding: f64,
+        },
+    }
+
+    /// Box padding (angstroms) used by the PyMOL plugin
+    fn default_box_padding() -> f64 {
+        3.5
+    }
+
+    /// Sanitization of the `pdb` structure: only ATOM and HETATM records of the
+    /// selected model are kept, other records (HEADER, REMARK, CONECT...) are
+    /// removed. The result is written as PDB.
+    #[derive(Serialize, Deserialize, Debug, Clone, Default, JsonSchema)]
+    #[serde(default, deny_unknown_fields)]
+    pub struct Sanitize {
+        /// Also remove water molecules
+        remove_waters: bool,
+    }
+
+    /// Formats accepted for the `pdb` structure. mmCIF structures are converted
+    /// to PDB before running parKVFinder.
+    #[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy, JsonSchema)]
+    #[serde(rename_all = "lowercase")]
+    pub enum StructureFormat {
+        Pdb,
+        Mmcif,
     }
 
     impl Input {
         /// Check if parameters received from a client (users) are ok.
-        /// Some parameters have constraints in this web service to prevent heavy
-        /// jobs that could block or slow down the server.
-        fn check(&self) -> Result<(), &str> {
+        /// Some parameters have constraints (`policy`) in this web service to
+        /// prevent heavy jobs that could block or slow down the server.
+        /// All rules are checked and every violation is reported at once.
+        /// Returns the model of `pdb` to be processed and the `model` value that
+        /// selects it (see `structure`).
+        fn check(&self, policy: &ValidationPolicy) -> Result<(pdb::Model, Option<u32>), KvError> {
+            match self.violations(policy) {
+                (violations, Some(selection)) if violations.is_empty() => Ok(selection),
+                (violations, _) => Err(KvError::Invalid(violations)),
+            }
+        }
+
+        fn violations(&self, policy: &ValidationPolicy) -> (Vec<Violation>, Option<(pdb::Model, Option<u32>)>) {
+            let mut violations = Vec::new();
+            let mut violation = |code, field: &str, message: &str| {
+                violations.push(Violation::new(code, field, message))
+            };
             // Compare Whole protein and Box modes
             if self.settings.modes.whole_protein_mode == self.settings.modes.box_mode {
-                return Err(
+                violation(
+                    ErrorCode::ModesConflict,
+                    "settings.modes.box_mode",
                     "Invalid parameters file! Whole protein and box modes cannot be equal!",
                 );
             }
             // Compare resolution mode
-            if self.settings.modes.resolution_mode != KVSResolution::Low {
-                return Err("Invalid parameters file! Resolution mode is restricted to Low option on this web service!");
+            if !policy.resolution_modes.contains(&self.settings.modes.resolution_mode) {
+                let modes: Vec<String> = policy
+                    .resolution_modes
+                    .iter()
+                    .map(|m| format!("{:?}", m))
+                    .collect();
+                violation(
+                    ErrorCode::ResolutionRestricted,
+                    "settings.modes.resolution_mode",
+                    &format!(
+                        "Invalid parameters file! Resolution mode is restricted to {} option{} on this web service!",
+                        modes.join(", "),
+                        if modes.len() > 1 { "s" } else { "" }
+                    ),
+                );
             }
             // Probe In
-            if self.settings.probes.probe_in < 0.0 || self.settings.probes.probe_in > 5.0 {
-                return Err("Invalid parameters file! Probe In must be between 0 and 5!");
+            if self.settings.probes.probe_in < 0.0 || self.settings.probes.probe_in > policy.probe_in_max {
+                violation(
+                    ErrorCode::ProbeInRange,
+                    "settings.probes.probe_in",
+                    &format!(
+                        "Invalid parameters file! Probe In must be between 0 and {}!",
+                        policy.probe_in_max
+                    ),
+                );
             }
             // Probe Out
-            if self.settings.probes.probe_out < 0.0 || self.settings.probes.probe_out > 50.0 {
-                return Err("Invalid parameters file! Probe Out must be between 0 and 50!");
+            if self.settings.probes.probe_out < 0.0
+                || self.settings.probes.probe_out > policy.probe_out_max
+            {
+                violation(
+                    ErrorCode::ProbeOutRange,
+                    "settings.probes.probe_out",
+                    &format!(
+                        "Invalid parameters file! Probe Out must be between 0 and {}!",
+                        policy.probe_out_max
+                    ),
+                );
             }
             // Compare probes
             if self.settings.probes.probe_out < self.settings.probes.probe_in {
-                return Err("Invalid parameters file! Probe Out must be greater than Probe In!");
+                violation(
+                    ErrorCode::ProbeOutLessThanProbeIn,
+                    "settings.probes.probe_out",
+                    "Invalid parameters file! Probe Out must be greater than Probe In!",
+                );
             }
             // Removal distance
             if self.settings.cutoffs.removal_distance < 0.0
-                || self.settings.cutoffs.removal_distance > 10.0
+                || self.settings.cutoffs.removal_distance > policy.removal_distance_max
             {
-                return Err("Invalid parameters file! Removal distance must be between 0 and 10!");
+                violation(
+                    ErrorCode::RemovalDistanceRange,
+                    "settings.cutoffs.removal_distance",
+                    &format!(
+                        "Invalid parameters file! Removal distance must be between 0 and {}!",
+                        policy.removal_distance_max
+                    ),
+                );
             }
             // Volume Cutoff
             if self.settings.cutoffs.volume_cutoff < 0.0 {
-                return Err("Invalid parameters file! Volume cutoff must be greater than 0!");
+                violation(
+                    ErrorCode::VolumeCutoffRange,
+                    "settings.cutoffs.volume_cutoff",
+                    "Invalid parameters file! Volume cutoff must be greater than 0!",
+                );
             }
             // Cavity representation
-            if self.settings.modes.kvp_mode {
-                return Err("Invalid parameters file! Cavity Representation (kvp_mode) must be false on this webservice!");
+            if self.settings.modes.kvp_mode && !policy.allow_kvp_mode {
+                violation(
+                    ErrorCode::KvpModeRestricted,
+                    "settings.modes.kvp_mode",
+                    "Invalid parameters file! Cavity Representation (kvp_mode) must be false on this webservice!",
+                );
             }
             // Ligand mode and pdb
-            if self.settings.modes.ligand_mode && self.pdb_ligand == None {
-                return Err("Invalid parameters file! A ligand must be provided when Ligand mode is set to true!");
-            } else if !self.settings.modes.ligand_mode && self.pdb_ligand != None {
-                return Err("Invalid parameters file! The Ligand mode must be set to true when providing a ligand!");
+            if self.settings.modes.ligand_mode && self.pdb_ligand.is_none() {
+                violation(
+                    ErrorCode::LigandMissing,
+                    "pdb_ligand",
+                    "Invalid parameters file! A ligand must be provided when Ligand mode is set to true!",
+                );
+            } else if !self.settings.modes.ligand_mode
+                && self.pdb_ligand.is_some()
+                && !matches!(self.box_selection, Some(BoxSelection::Ligand { .. }))
+            {
+                violation(
+                    ErrorCode::LigandModeDisabled,
+                    "settings.modes.ligand_mode",
+                    "Invalid parameters file! The Ligand mode must be set to true when providing a ligand!",
+                );
             }
             // Ligand Cutoff
             if self.settings.cutoffs.ligand_cutoff <= 0.0 {
-                return Err("Invalid parameters file! Ligand cutoff must be greater than 0!");
+                violation(
+                    ErrorCode::LigandCutoffRange,
+                    "settings.cutoffs.ligand_cutoff",
+                    "Invalid parameters file! Ligand cutoff must be greater than 0!",
+                );
             }
 
+            // Box selection
+            if self.box_selection.is_some() && !self.settings.modes.box_mode {
+                violation(
+                    ErrorCode::BoxModeDisabled,
+                    "settings.modes.box_mode",
+                    "Invalid parameters file! The Box mode must be set to true when providing a box selection!",
+                );
+            }
+            let selected = self.selected_box();
+            for v in selected.as_ref().err().into_iter().flatten() {
+                violation(v.code, &v.field, &v.message);
+            }
+            // boxes are not computed from an invalid selection, so they are not checked
+            let check_boxes = self.settings.modes.box_mode && selected.is_ok();
+            // Box geometry
+            if check_boxes {
+                let visible = box_violation(
+                    self.settings.visiblebox.corners(),
+                    "settings.visiblebox",
+                    "visible",
+                    policy.box_tolerance,
+                );
+                let internal = box_violation(
+                    self.settings.internalbox.corners(),
+                    "settings.internalbox",
+                    "internal",
+                    policy.box_tolerance,
+                );
+                let padding = match (&visible, &internal) {
+                    (None, None) => padding_violation(
+                        &self.settings.visiblebox,
+                        &self.settings.internalbox,
+                        self.settings.probes.probe_out,
+                        policy.box_tolerance,
+                    ),
+                    _ => None,
+                };
+                for v in [visible, internal, padding].iter().flatten() {
+                    violation(v.code, &v.field, &v.message);
+                }
+            }
+            // Box volume
+            if check_boxes && self.settings.internalbox.volume() > policy.max_box_volume {
+                violation(
+                    ErrorCode::BoxVolumeRange,
+                    "settings.internalbox",
+                    &format!(
+                        "Invalid parameters file! Box volume must be at most {} cubic angstroms!",
+                        policy.max_box_volume
+                    ),
+                );
+            }
+
+            let (model, serial) = match self.structure() {
+                Ok(selection) => selection,
+                Err(v) => {
+                    violation(v.code, &v.field, &v.message);
+                    return (violations, None);
+                }
+            };
+            // Number of atoms
+            if model.atoms.len() > policy.max_atoms {
+                violation(
+                    ErrorCode::TooManyAtoms,
+                    "pdb",
+                    &format!(
+                        "Invalid PDB file! The structure has {} atoms, the limit on this web service is {}!",
+                        model.atoms.len(),
+                        policy.max_atoms
+                    ),
+                );
+            }
+            // mmCIF and sanitized structures are written as PDB for parKVFinder
+            if self.structure_format() == StructureFormat::Mmcif || self.sanitize.is_some() {
+                if let Err(e) = pdb::to_pdb(std::slice::from_ref(&model)) {
+                    violation(ErrorCode::PdbColumnOverflow, "pdb", &column_overflow(self.structure_format(), &e));
+                }
+            }
             // Box inside pdb boundaries
-            if self.settings.modes.box_mode {
-                if let Ok(pdb_boundaries) = self.get_pdb_boundaries() {
-                    if !pdb_boundaries.contains(&self.settings.internalbox) {
-                        return Err("Invalid parameters file! Inconsistent box coordinates!");
-                    }
-                } else {
-                    return Err("parsing error");
+            if check_boxes && !self.get_pdb_boundaries(&model).contains(&self.settings.internalbox) {
+                violation(
+                    ErrorCode::BoxOutOfBounds,
+                    "settings.internalbox",
+                    "Invalid parameters file! Inconsistent box coordinates!",
+                );
+            }
+            (violations, Some((model, serial)))
+        }
+
+        /// Apply the requested sanitization, replacing `pdb` by the checked `model`.
+        fn sanitized(self, model: &pdb::Model) -> Result<Input, KvError> {
+            match self.sanitize {
+                Some(_) => Ok(Input {
+                    pdb: pdb::to_pdb(std::slice::from_ref(model)).map_err(|e| {
+                        let message = column_overflow(self.structure_format(), &e);
+                        invalid(ErrorCode::PdbColumnOverflow, "pdb", &message)
+                    })?,
+                    format: Some(StructureFormat::Pdb),
+                    model: None,
+                    ..self
+                }),
+                None => Ok(self),
+            }
+        }
+
+        /// Format of `pdb`: the declared one or mmCIF if the content looks like a
+        /// mmCIF file, otherwise PDB.
+        fn structure_format(&self) -> StructureFormat {
+            match self.format {
+                Some(format) => format,
+                None if mmcif::is_mmcif(&self.pdb) => StructureFormat::Mmcif,
+                None => StructureFormat::Pdb,
+            }
+        }
+
+        /// Parse `pdb` and get the selected model, without water molecules if
+        /// requested by `sanitize`. The model has at least one atom.
+        /// Also returns the `model` value that selects it: None for the first
+        /// model of the file, which is selected by default.
+        fn structure(&self) -> Result<(pdb::Model, Option<u32>), Violation> {
+            let (models, name, records) = match self.structure_format() {
+                StructureFormat::Pdb => (pdb::parse(&self.pdb), "PDB", "ATOM or HETATM records"),
+                StructureFormat::Mmcif => (mmcif::parse(&self.pdb), "mmCIF", "_atom_site records"),
+            };
+            let models = models.map_err(|e| {
+                Violation::new(ErrorCode::PdbParsing, "pdb", &format!("Invalid {} file! {}!", name, e))
+            })?;
+            let empty = |message: &str| {
+                Violation::new(
+                    ErrorCode::EmptyStructure,
+                    "pdb",
+                    &format!("Invalid {} file! {}!", name, message),
+                )
+            };
+            let mut model = match pdb::find_model(&models, self.model) {
+                Some(model) => model.clone(),
+                None if models.is_empty() => return Err(empty(&format!("No {} found", records))),
+                None => {
+                    return Err(Violation::new(
+                        ErrorCode::ModelNotFound,
+                        "model",
+                        &format!("Invalid {} file! MODEL {} not found!", name, self.model.unwrap_or(0)),
+                    ))
+                }
+            };
+            if self.sanitize.as_ref().is_some_and(|s| s.remove_waters) {
+                model.atoms.retain(|a| !a.is_water());
+                if model.atoms.is_empty() {
+                    return Err(empty("Only water molecules found"));
                 }
             }
-            Ok(())
+            let serial = Some(model.serial).filter(|&serial| serial != models[0].serial);
+            Ok((model, serial))
         }
 
         /// Get boundaries of a PDB file.
         /// Boundaries are defined as minimum/maximum values for each cartesian axis with
         /// subtraction/addition of probe value plus 20 angstrons.
-        fn get_pdb_boundaries(&self) -> Result<PdbBoundaries, &str> {
-            let coords: Option<PdbBoundaries> = self
-                .pdb
-                .lines()
-                .filter(|s| s.starts_with("ATOM"))
-                .map(|s| {
-                    let (x, y, z) = (
-                        s.get(30..38).unwrap_or("").trim().parse::<f64>()?,
-                        s.get(38..46).unwrap_or("").trim().parse::<f64>()?,
-                        s.get(46..54).unwrap_or("").trim().parse::<f64>()?,
-                    );
-                    Ok((x, y, z))
-                })
-                .fold(None as Option<PdbBoundaries>, |state, p: Result<(f64, f64, f64), ParseFloatError>| {
-                    let p = p.expect("failed parsing pdb coords");
-                    match state {
-                        None => Some(PdbBoundaries {
-                            x_min: p.0,
-                            x_max: p.0,
-                            y_min: p.1,
-                            y_max: p.1,
-                            z_min: p.2,
-                            z_max: p.2,
-                        }),
-                        Some(s) => Some(PdbBoundaries {
-                            x_min: s.x_min.min(p.0),
-                            x_max: s.x_max.max(p.0),
-                            y_min: s.y_min.min(p.1),
-                            y_max: s.y_max.max(p.1),
-                            z_min: s.z_min.min(p.2),
-                            z_max: s.z_max.max(p.2),
-                        }),
-                    }
-                });
-
-            match coords {
-                Some(c) => Ok(PdbBoundaries {
-                    // we define pdb boundaries adding probe out and also 20 angstrons to each
-                    // direction
-                    x_min: c.x_min - (self.settings.probes.probe_out + 20.0),
-                    x_max: c.x_max + (self.settings.probes.probe_out + 20.0),
-                    y_min: c.y_min - (self.settings.probes.probe_out + 20.0),
-                    y_max: c.y_max + (self.settings.probes.probe_out + 20.0),
-                    z_min: c.z_min - (self.settings.probes.probe_out + 20.0),
-                    z_max: c.z_max + (self.settings.probes.probe_out + 20.0),
-                }),
-                None => Err("parsing error"),
+        /// Atoms (ATOM and HETATM records) of the selected model are considered.
+        fn get_pdb_boundaries(&self, model: &pdb::Model) -> PdbBoundaries {
+            // we define pdb boundaries adding probe out and also 20 angstrons to each
+            // direction
+            PdbBoundaries::of(&model.atoms, self.settings.probes.probe_out + 20.0)
+        }
+
+        /// Canonical form of a checked input, so equivalent inputs are stored (and
+        /// hashed) the same way: structures have "\n" line endings, no trailing
+        /// whitespace and no trailing empty lines, the structure format is set and
+        /// settings not used by the selected modes take their default values (boxes
+        /// in whole protein mode, step size unless resolution mode is Off and
+        /// ligand cutoff unless ligand mode is set), `model` is the value that
+        /// selects the checked model (`serial`) and `box_selection` is removed, as
+        /// the boxes computed from it are in `settings` (with the ligand it was
+        /// computed from, unless ligand mode is set).
+        fn canonical(mut self, serial: Option<u32>) -> Input {
+            self.format = Some(self.structure_format());
+            self.model = serial;
+            self.box_selection = None;
+            self.pdb = normalize_text(&self.pdb);
+            self.pdb_ligand = self.pdb_ligand.as_deref().map(normalize_text);
+            let settings = &mut self.settings;
+            if !settings.modes.box_mode {
+                settings.visiblebox = KVSVisiblebox::default();
+                settings.internalbox = KVSInternalbox::default();
+            }
+            if settings.modes.resolution_mode != KVSResolution::Off {
+                settings.step_size = KVSStepSize::default();
+            }
+            if !settings.modes.ligand_mode {
+                settings.cutoffs.ligand_cutoff = KVSCutoffs::default().ligand_cutoff;
+                self.pdb_ligand = None;
+            }
+            self
+        }
+
+        /// Set `visiblebox` and `internalbox` to the boxes of `box_selection`, if
+        /// any. They are left unchanged if the selection is invalid, which is
+        /// reported by `check`.
+        fn with_selected_box(mut self) -> Input {
+            if let Ok(Some((visiblebox, internalbox))) = self.selected_box() {
+                self.settings.visiblebox = visiblebox;
+                self.settings.internalbox = internalbox;
+            }
+            self
+        }
+
+        /// Boxes computed from `box_selection`, if any. The visible box is the
+        /// axis-aligned box around the selected atoms padded by `padding`, and the
+        /// internal box is the visible box padded by Probe Out.
+        /// Fails with the violations of the selection; they are empty if `pdb`
+        /// cannot be read, as it is already reported by `structure`.
+        fn selected_box(&self) -> Result<Option<(KVSVisiblebox, KVSInternalbox)>, Vec<Violation>> {
+            let (atoms, padding, field) = match &self.box_selection {
+                None => return Ok(None),
+                Some(BoxSelection::Residues {
+                    chain,
+                    residues,
+                    padding,
+                }) => {
+                    let (model, _) = self.structure().map_err(|_| Vec::new())?;
+                    let atoms: Vec<pdb::Atom> = model
+                        .atoms
+                        .into_iter()
+                        .filter(|a| &a.chain_id == chain && residues.contains(&a.res_seq))
+                        .collect();
+                    (atoms, *padding, "box_selection.residues")
+                }
+                Some(BoxSelection::Ligand { padding }) => {
+                    let ligand = self.pdb_ligand.as_ref().ok_or_else(|| {
+                        vec![Violation::new(
+                            ErrorCode::LigandMissing,
+                            "pdb_ligand",
+                            "Invalid parameters file! A ligand must be provided to select the box around it!",
+                        )]
+                    })?;
+                    let models = pdb::parse(ligand).map_err(|e| {
+                        vec![Violation::new(
+                            ErrorCode::PdbParsing,
+                            "pdb_ligand",
+                            &format!("Invalid ligand PDB file! {}!", e),
+                        )]
+                    })?;
+                    let atoms = models.into_iter().next().map(|m| m.atoms).unwrap_or_default();
+                    (atoms, *padding, "box_selection.ligand")
+                }
+            };
+            let mut violations = Vec::new();
+            if atoms.is_empty() {
+                violations.push(Violation::new(
+                    ErrorCode::BoxSelectionEmpty,
+                    field,
+                    "Invalid parameters file! No atoms found in the box selection!",
+                ));
+            }
+            if padding < 0.0 {
+                violations.push(Violation::new(
+                    ErrorCode::BoxPaddingRange,
+                    &format!("{}.padding", field),
+                    "Invalid parameters file! Box padding must be greater than 0!",
+                ));
             }
+            if !violations.is_empty() {
+                return Err(violations);
+            }
+            let [p1, p2, p3, p4] = PdbBoundaries::of(&atoms, padding).corners();
+            let visiblebox = KVSVisiblebox { p1, p2, p3, p4 };
+            let [p1, p2, p3, p4] = PdbBoundaries::of(&atoms, padding + self.settings.probes.probe_out).corners();
+            let internalbox = KVSInternalbox { p1, p2, p3, p4 };
+            Ok(Some((visiblebox, internalbox)))
         }
     }
 
+    /// Text with "\n" line endings, without trailing whitespace in lines and
+    /// without trailing empty lines
+    fn normalize_text(text: &str) -> String {
+        let mut normalized = String::with_capacity(text.len());
+        for line in text.trim_end().lines() {
+            normalized.push_str(line.trim_end());
+            normalized.push('\n');
+        }
+        normalized
+    }
+
+    fn invalid(code: ErrorCode, field: &str, message: &str) -> KvError {
+        KvError::Invalid(vec![Violation::new(code, field, message)])
+    }
+
+    /// Message of a structure that cannot be written as PDB for parKVFinder
+    fn column_overflow(format: StructureFormat, e: &pdb::PdbError) -> String {
+        let name = match format {
+            StructureFormat::Pdb => "PDB",
+            StructureFormat::Mmcif => "mmCIF",
+        };
+        format!("Invalid {} file! {}! parKVFinder reads PDB files", name, e)
+    }
+
     #[derive(Serialize, Deserialize, Debug, Clone)]
     pub struct Output {
         pdb_kv: String,
         report: String,
         log: String,
     }
+
+    /// Kind of a job failure, for clients to handle failures without parsing
+    /// `reason`. Serialized in SCREAMING_SNAKE_CASE and must not be renamed.
+    #[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy)]
+    #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+    pub enum FailureKind {
+        /// parKVFinder took longer than the worker timeout
+        Timeout,
+        /// parKVFinder exceeded the CPU time limit
+        CpuLimit,
+        /// parKVFinder exited with an error (or without writing its results)
+        ExitCode,
+        /// parKVFinder was killed by a signal
+        Signal,
+        /// parKVFinder could not run (or the worker failed before running it)
+        NotRun,
+    }
+
+    /// Why a job failed. Like `Output`, text is kept compressed in queue.
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct Failure {
+        /// What went wrong, e.g. "parKVFinder exited with code 1"
+        pub reason: String,
+        pub kind: FailureKind,
+        /// parKVFinder exit code (null if it did not run or was killed by a signal)
+        exit_code: Option<i32>,
+        /// End of parKVFinder standard output
+        stdout: String,
+        /// End of parKVFinder standard error
+        stderr: String,
+        /// parKVFinder log, if it was written
+        log: Option<String>,
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        const TOLERANCE: f64 = 0.1;
+        const PROBE_OUT: f64 = 4.0;
+
+        fn point(x: f64, y: f64, z: f64) -> KVSBoxPoint {
+            KVSBoxPoint { x, y, z }
+        }
+
+        /// Visible box with edges of 10, 6 and 4 angstroms along axes rotated
+        /// 45 degrees around z, and its internal box padded by `PROBE_OUT`
+        fn rotated_boxes() -> (KVSVisiblebox, KVSInternalbox) {
+            let k = std::f64::consts::FRAC_1_SQRT_2;
+            let axes = [point(k, k, 0.0), point(-k, k, 0.0), point(0.0, 0.0, 1.0)];
+            let p1 = point(1.0, 2.0, 3.0);
+            let visible = KVSVisiblebox {
+                p2: p1.add_scaled(&axes[0], 10.0),
+                p3: p1.add_scaled(&axes[1], 6.0),
+                p4: p1.add_scaled(&axes[2], 4.0),
+                p1,
+            };
+            let padded = |corner: &KVSBoxPoint, n: usize| {
+                axes.iter().enumerate().fold(corner.clone(), |p, (a, axis)| {
+                    p.add_scaled(axis, if n == a + 1 { PROBE_OUT } else { -PROBE_OUT })
+                })
+            };
+            let internal = KVSInternalbox {
+                p1: padded(&visible.p1, 0),
+                p2: padded(&visible.p2, 1),
+                p3: padded(&visible.p3, 2),
+                p4: padded(&visible.p4, 3),
+            };
+            (visible, internal)
+        }
+
+        #[test]
+        fn rotated_boxes_are_accepted() {
+            let (visible, internal) = rotated_boxes();
+            assert!(box_violation(visible.corners(), "settings.visiblebox", "visible", TOLERANCE).is_none());
+            assert!(box_violation(internal.corners(), "settings.internalbox", "internal", TOLERANCE).is_none());
+            assert!(padding_violation(&visible, &internal, PROBE_OUT, TOLERANCE).is_none());
+        }
+
+        #[test]
+        fn corner_of_a_non_perpendicular_edge_is_reported() {
+            let (mut visible, _) = rotated_boxes();
+            // p4 leans 1 angstrom towards p2
+            let k = std::f64::consts::FRAC_1_SQRT_2;
+            visible.p4 = visible.p4.add_scaled(&point(k, k, 0.0), 1.0);
+            let v = box_violation(visible.corners(), "settings.visiblebox", "visible", TOLERANCE).unwrap();
+            assert_eq!(v.code, ErrorCode::BoxNotRectangular);
+            assert_eq!(v.field, "settings.visiblebox.p4");
+            assert_eq!(
+                v.message,
+                "Invalid parameters file! Corner p4 of the visible box is 1.00 angstroms off: edge p1-p4 must be perpendicular to edge p1-p2!"
+            );
+        }
+
+        #[test]
+        fn corner_coinciding_with_p1_is_reported() {
+            let (_, mut internal) = rotated_boxes();
+            internal.p3 = internal.p1.add_scaled(&point(0.0, 0.0, 1.0), TOLERANCE / 2.0);
+            let v = box_violation(internal.corners(), "settings.internalbox", "internal", TOLERANCE).unwrap();
+            assert_eq!(v.code, ErrorCode::BoxDegenerate);
+            assert_eq!(v.field, "settings.internalbox.p3");
+            assert_eq!(
+                v.message,
+                "Invalid parameters file! Corner p3 of the internal box must not coincide with corner p1!"
+            );
+        }
+
+        #[test]
+        fn internal_box_corner_off_the_padding_is_reported() {
+            let (visible, mut internal) = rotated_boxes();
+            internal.p3.z += TOLERANCE / 2.0;
+            assert!(padding_violation(&visible, &internal, PROBE_OUT, TOLERANCE).is_none());
+            internal.p3.z += TOLERANCE;
+            let v = padding_violation(&visible, &internal, PROBE_OUT, TOLERANCE).unwrap();
+            assert_eq!(v.code, ErrorCode::BoxPaddingMismatch);
+            assert_eq!(v.field, "settings.internalbox.p3");
+            assert!(v.message.ends_with("it is 0.15 angstroms off!"), "{}", v.message);
+        }
+    }
 }
 
+pub use crate::kvweb::config;
+pub use crate::kvweb::error;
+pub use crate::kvweb::mmcif;
+pub use crate::kvweb::pdb;
+pub use crate::kvweb::queue;
+pub use crate::kvweb::report;
 pub use crate::kvweb::webserver;
 pub use crate::kvweb::worker;